@@ -0,0 +1,261 @@
+//! 参数自动化
+//!
+//! 每个参数带一条由断点（`musiblock::Node`）组成的自动化轨道。启用后 `Param::tick`
+//! 按图流的 `Time` 逐帧求值，不经过平滑；编辑线程随时替换轨道，音频线程在下一个缓冲区生效。
+//! 录制时旋钮的改动写入轨道，播放跟随旋钮。
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::musiblock::{CurveType, Node};
+
+/// 按时间排序的断点，时间以秒为单位
+#[derive(Clone, Default)]
+pub struct Lane {
+    nodes: Vec<Node>,
+}
+
+impl Lane {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from(mut nodes: Vec<Node>) -> Self {
+        nodes.sort_by(|a, b| a.t.total_cmp(&b.t));
+        Lane { nodes }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// 插入断点，同一时间的断点会被替换
+    pub fn insert(&mut self, node: Node) {
+        let index = self.nodes.partition_point(|n| n.t < node.t);
+        match self.nodes.get_mut(index) {
+            Some(n) if n.t == node.t => *n = node,
+            _ => self.nodes.insert(index, node),
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> Node {
+        self.nodes.remove(index)
+    }
+
+    /// 删除时间在 `start` 之后、不晚于 `end` 的断点
+    pub fn clear_range(&mut self, start: f32, end: f32) {
+        self.nodes.retain(|n| n.t <= start || n.t > end);
+    }
+
+    /// 在 `secs` 处的值，轨道为空时返回 `None`，首尾之外保持端点的值
+    pub fn value_at(&self, secs: f32) -> Option<f32> {
+        let index = self.nodes.partition_point(|n| n.t <= secs);
+        let (Some(a), Some(b)) = (index.checked_sub(1).map(|i| &self.nodes[i]), self.nodes.get(index)) else {
+            return self.nodes.get(index.saturating_sub(1)).map(|n| n.v);
+        };
+        // 曲线类型由前一个断点决定，和 `Envelope` 一样目前只实现了线性，其余也按线性处理
+        Some(a.v + (secs - a.t) * (b.v - a.v) / (b.t - a.t))
+    }
+}
+
+/// 参数与自动化轨道之间共享的状态
+pub struct Automation {
+    lane: Mutex<Arc<Lane>>,
+    /// 音频线程换下的轨道，由编辑线程下一次修改轨道时释放
+    retired: Mutex<Option<Arc<Lane>>>,
+    /// 只在持有 `lane` 的锁时增加
+    version: AtomicU64,
+    enabled: AtomicBool,
+    recording: AtomicBool,
+    /// 当前缓冲区起点（采样）与采样率，由图流在每次运行前写入
+    position: AtomicU64,
+    sample_rate: AtomicU32,
+    /// 上一次录制的位置与值，`u64::MAX` 表示本次录制还没有写入
+    last_recorded: AtomicU64,
+    last_value: AtomicU32,
+}
+
+impl Default for Automation {
+    fn default() -> Self {
+        Automation {
+            lane: Mutex::new(Arc::new(Lane::new())),
+            retired: Mutex::new(None),
+            version: AtomicU64::new(0),
+            enabled: AtomicBool::new(false),
+            recording: AtomicBool::new(false),
+            position: AtomicU64::new(0),
+            sample_rate: AtomicU32::new(48000),
+            last_recorded: AtomicU64::new(u64::MAX),
+            last_value: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Automation {
+    pub fn lane(&self) -> Arc<Lane> {
+        Arc::clone(&self.lane.lock().unwrap())
+    }
+
+    /// 替换整条轨道，播放中也可以调用
+    pub fn set_lane(&self, lane: Lane) {
+        let mut current = self.lane.lock().unwrap();
+        *current = Arc::new(lane);
+        self.version.fetch_add(1, Ordering::Release);
+        self.retired.lock().unwrap().take();
+    }
+
+    /// 在当前轨道的副本上编辑后替换
+    pub fn edit(&self, f: impl FnOnce(&mut Lane)) {
+        let mut lane = self.lane.lock().unwrap();
+        let mut edited = Lane::clone(&lane);
+        f(&mut edited);
+        *lane = Arc::new(edited);
+        self.version.fetch_add(1, Ordering::Release);
+        self.retired.lock().unwrap().take();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    /// 停止录制时把最后一次的值保持到当前位置
+    pub fn set_recording(&self, recording: bool) {
+        if !recording && self.is_recording() && self.last_recorded.load(Ordering::Relaxed) != u64::MAX {
+            self.record(f32::from_bits(self.last_value.load(Ordering::Relaxed)));
+        }
+        self.last_recorded.store(u64::MAX, Ordering::Relaxed);
+        self.recording.store(recording, Ordering::Relaxed);
+    }
+
+    /// 当前播放位置（秒），精确到缓冲区
+    pub fn position_secs(&self) -> f32 {
+        self.position.load(Ordering::Relaxed) as f32 / self.sample_rate.load(Ordering::Relaxed) as f32
+    }
+
+    /// 把旋钮的值写到当前位置，覆盖上一次录制以来的断点
+    pub(crate) fn record(&self, value: f32) {
+        let position = self.position.load(Ordering::Relaxed);
+        let last = self.last_recorded.swap(position, Ordering::Relaxed);
+        self.last_value.store(value.to_bits(), Ordering::Relaxed);
+        let secs = self.position_secs();
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed) as f32;
+        self.edit(|lane| {
+            if last < position {
+                lane.clear_range(last as f32 / sample_rate, secs);
+            }
+            lane.insert(Node { t: secs, v: value, curve: CurveType::Linear, if_hold: false });
+        });
+    }
+
+    pub(crate) fn begin_buffer(&self, position: u64, sample_rate: u32) {
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+        self.position.store(position, Ordering::Relaxed);
+    }
+
+    pub(crate) fn position(&self) -> (u64, u32) {
+        (self.position.load(Ordering::Relaxed), self.sample_rate.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// 音频线程换上最新的轨道并返回它的版本，换下的轨道放进回收槽，不在音频线程上释放
+    ///
+    /// 编辑线程持有锁或回收槽还没清空时返回 `None`，沿用旧的轨道。
+    pub(crate) fn try_swap_lane(&self, lane: &mut Arc<Lane>) -> Option<u64> {
+        let current = self.lane.try_lock().ok()?;
+        let mut retired = self.retired.try_lock().ok()?;
+        if retired.is_some() {
+            return None;
+        }
+        *retired = Some(std::mem::replace(lane, Arc::clone(&current)));
+        Some(self.version.load(Ordering::Acquire))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{IOData, Processor, Time};
+    use crate::graph_flow::GraphFlowBuilder;
+    use crate::param::{Param, ParamHandle, ParamSpec};
+    use crate::approx_eq;
+
+    fn node(t: f32, v: f32) -> Node {
+        Node { t, v, curve: CurveType::Linear, if_hold: false }
+    }
+
+    struct Knob(Param);
+
+    impl Processor for Knob {
+        fn prepare(&mut self, sample_rate: u32, _max_buffer: usize, _num_channels: usize) {
+            self.0.prepare(sample_rate);
+        }
+
+        fn params(&self) -> Vec<ParamHandle> {
+            vec![self.0.handle()]
+        }
+
+        fn process(&mut self, _time: Time, _inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+            for frame in 0..outputs.num_frames() {
+                let value = self.0.tick();
+                outputs.channels_mut(0).for_each(|samples| samples[frame] = value);
+            }
+        }
+    }
+
+    #[test]
+    fn lane_values() {
+        let lane = Lane::from(vec![node(1.0, 2.0), node(0.0, 0.0)]);
+        assert_eq!(lane.value_at(-1.0), Some(0.0));
+        assert_eq!(lane.value_at(0.5), Some(1.0));
+        assert_eq!(lane.value_at(3.0), Some(2.0));
+        assert_eq!(Lane::new().value_at(0.0), None);
+    }
+
+    #[test]
+    fn automate_and_record() {
+        let mut gf = GraphFlowBuilder { sample_rate: 10, buffer_size: 4, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let knob = gf.add_block(Knob(Param::new(ParamSpec::new("cutoff", 0.0, 100.0, 0.0)))).unwrap();
+        gf.to_output(knob.port(0)).unwrap();
+        let cutoff = gf.param(&knob, "cutoff").unwrap();
+        let automation = cutoff.automation();
+        automation.set_lane(Lane::from(vec![node(0.0, 0.0), node(1.0, 10.0)]));
+        automation.set_enabled(true);
+        let first = automation.lane();
+
+        let mut output = vec![0.0; 4];
+        let approx = |output: &[f32], expected: [f32; 4]| output.iter().zip(expected).all(|(a, b)| approx_eq(*a, b));
+        gf.run(4, &mut output);
+        assert!(approx(&output, [0.0, 1.0, 2.0, 3.0]));
+
+        // 播放中编辑，下一个缓冲区生效
+        automation.edit(|lane| lane.insert(node(1.0, 50.0)));
+        output.fill(0.0);
+        gf.run(4, &mut output);
+        assert!(approx(&output, [20.0, 25.0, 30.0, 35.0]));
+        // 换下的轨道留在回收槽里，由编辑线程下一次修改时释放
+        assert_eq!(Arc::strong_count(&first), 2);
+
+        // 录制：播放跟随旋钮，旋钮的值写入当前位置
+        automation.set_recording(true);
+        cutoff.set(70.0);
+        assert_eq!(Arc::strong_count(&first), 1);
+        output.fill(0.0);
+        gf.run(4, &mut output);
+        assert!(output.iter().all(|&v| v == 70.0));
+        automation.set_recording(false);
+        let lane = automation.lane();
+        assert_eq!((lane.value_at(0.6), lane.value_at(0.8)), (Some(70.0), Some(70.0)));
+        assert!(approx_eq(lane.value_at(0.9).unwrap(), 60.0));
+    }
+}
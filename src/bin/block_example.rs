@@ -1,20 +1,45 @@
 use cpal::traits::{DeviceTrait, HostTrait};
 use musiforge::{
-    block::{IOData, Time}, create_stream, frame_block, graph_flow::*
+    block::{IOData, Processor, Time}, create_stream, frame_block, graph_flow::*,
+    param::{Param, ParamHandle, ParamSpec},
 };
 use plotters::prelude::*;
 
-fn osc(mut time: Time, inputs: &IOData, outputs: &mut IOData, num_channels: usize) {
-    // let freq = inputs[0][0];
-    let freq = 440.0;
-    // println!("freq: {}", freq);
-    for frame in outputs[0].chunks_mut(num_channels) {
-        let val = time.get_phase_by_freq(freq).sin();
-        // println!("val: {}", val);
-        for sample in frame.iter_mut() {
-            *sample = val;
+struct Osc {
+    freq: Param,
+    phase: f32,
+    sample_rate: f32,
+}
+
+impl Osc {
+    fn new(freq: f32) -> Self {
+        let freq = Param::new(ParamSpec::new("freq", 330.0, 880.0, freq).unit("Hz"));
+        Osc { freq, phase: 0.0, sample_rate: 48000.0 }
+    }
+}
+
+impl Processor for Osc {
+    fn prepare(&mut self, sample_rate: u32, _max_buffer: usize, _num_channels: usize) {
+        self.sample_rate = sample_rate as f32;
+        self.freq.prepare(sample_rate);
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.freq.reset();
+    }
+
+    fn params(&self) -> Vec<ParamHandle> {
+        vec![self.freq.handle()]
+    }
+
+    fn process(&mut self, _time: Time, _inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+        for frame in 0..outputs.num_frames() {
+            let val = self.phase.sin();
+            outputs.channels_mut(0).for_each(|samples| samples[frame] = val);
+            let phase_step = std::f32::consts::TAU * self.freq.tick() / self.sample_rate;
+            self.phase = (self.phase + phase_step) % std::f32::consts::TAU;
         }
-        time.tick();
     }
 }
 
@@ -41,33 +66,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     let mut gf = builder.build();
 
-    let osc_id = gf.add_block(osc);
-    let osc_2_id = gf.add_block(frame_block!(osc_2));
-    let filter_id = gf.add_block(filter);
+    let osc_id = gf.add_block(Osc::new(440.0))?;
+    let osc_2_id = gf.add_block(frame_block!(osc_2))?;
+    let filter_id = gf.add_block(filter)?;
 
-    gf.connect(osc_id.port(0), filter_id.port(0));
-    gf.connect(osc_2_id.port(0), filter_id.port(0));
-    gf.to_output(filter_id.port(0));
+    gf.connect(osc_id.port(0), filter_id.port(0))?;
+    gf.connect(osc_2_id.port(0), filter_id.port(0))?;
+    gf.to_output(filter_id.port(0))?;
 
-    // let freq_listener = gf.add_listener(osc_id.port(0));
+    let freq = gf.param(&osc_id, "freq")?;
 
     println!("{:?}", gf);
 
+    // 图流留在 UI 线程上，播放中仍可编辑
+    let mut engine = gf.split();
     let stream = create_stream(480, 48000, move |datum: &mut [f32], _| {
-        gf.run(datum.len() as u32, datum);
+        engine.run(datum.len() as u32, datum);
     });
     stream();
-    std::thread::park();
-
 
-    // let app = Content::new(freq_listener);
+    let app = Content::new(freq);
 
-    // let options = eframe::NativeOptions::default();
-    // let _ = eframe::run_native(
-    //     "My egui Application",
-    //     options,
-    //     Box::new(|_cc| Ok(Box::new(app))),
-    // );
+    let options = eframe::NativeOptions::default();
+    let _ = eframe::run_native(
+        "My egui Application",
+        options,
+        Box::new(|_cc| Ok(Box::new(app))),
+    );
 
 
     Ok(())
@@ -81,17 +106,15 @@ struct BiBuffer {
 }
 
 pub struct Content {
-    freq_listener: Listener,
+    freq_param: ParamHandle,
     freq: f32,
 }
 
 impl Content {
-    fn new(freq_listener: Listener) -> Self {
-        freq_listener.send(440.0);
-
+    fn new(freq_param: ParamHandle) -> Self {
         Content {
-            freq_listener,
-            freq: 440.0,
+            freq: freq_param.get(),
+            freq_param,
         }
     }
 }
@@ -100,8 +123,10 @@ impl eframe::App for Content {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("ha");
-            if ui.add(egui::Slider::new(&mut self.freq, 330.0..=880.0)).changed() {
-                self.freq_listener.send(self.freq);
+            let spec = self.freq_param.spec();
+            let slider = egui::Slider::new(&mut self.freq, spec.min..=spec.max).suffix(&spec.unit);
+            if ui.add(slider).changed() {
+                self.freq_param.set(self.freq);
             }
         });
     }
@@ -1,10 +1,19 @@
 use std::ops::{Index, IndexMut};
-use std::sync::{mpsc, Arc};
+use std::cell::UnsafeCell;
+use std::sync::Arc;
 use std::collections::{HashMap, HashSet};
 
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+use crate::channel::{ChannelLayout, MAX_FRAME_CHANNELS};
+use crate::event::{EventBuffer, EVENT_CAPACITY};
+use crate::param::ParamHandle;
+use crate::project::BlockType;
+use crate::profile::Stats;
+use crate::switch::ModeHandle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct BlockId(Uuid);
 
 impl BlockId {
@@ -15,6 +24,17 @@ impl BlockId {
     pub fn port(&self, port: usize) -> Port {
         Port { block_id: *self, port }
     }
+
+    /// 按名字引用端口，连接时根据方向在输入或输出端口中查找
+    pub fn named(&self, name: &str) -> PortRef {
+        PortRef::Named(*self, name.to_string())
+    }
+}
+
+impl std::fmt::Display for BlockId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -43,6 +63,11 @@ impl Time {
         }
     }
 
+    /// 位于第 `sample` 帧的时间
+    pub(crate) fn at(sample_rate: u32, sample: u64) -> Self {
+        Time { sample, ..Time::new(sample_rate) }
+    }
+
     pub fn tick(&mut self) {
         self.sample += 1;
     }
@@ -55,13 +80,16 @@ impl Time {
         self.sample
     }
 
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
     pub fn as_secs_f32(&self) -> f32 {
         self.sample as f32 / self.sample_rate as f32
     }
 
     pub fn get_phase_by_freq(&self, freq: f32) -> f32 {
-        const TWO_PI: f32 = 6.283185307179586;
-        TWO_PI * self.as_secs_f32() * freq
+        std::f32::consts::TAU * self.as_secs_f32() * freq
     }
 }
 
@@ -71,44 +99,301 @@ impl Default for Time {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Port {
     pub block_id: BlockId,
     pub port: usize,
 }
 
+/// 连接时对端口的引用，可以是下标也可以是名字
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortRef {
+    Index(Port),
+    Named(BlockId, String),
+}
+
+impl PortRef {
+    pub fn block_id(&self) -> BlockId {
+        match self {
+            PortRef::Index(port) => port.block_id,
+            PortRef::Named(block_id, _) => *block_id,
+        }
+    }
+}
+
+impl From<Port> for PortRef {
+    fn from(port: Port) -> Self {
+        PortRef::Index(port)
+    }
+}
+
+impl From<(BlockId, &str)> for PortRef {
+    fn from((block_id, name): (BlockId, &str)) -> Self {
+        PortRef::Named(block_id, name.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SignalKind {
+    Audio,
+    Control,
+    Event,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PortDirection {
+    Input,
+    Output,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortSpec {
+    pub name: String,
+    pub kind: SignalKind,
+    /// 音频端口的声道布局，`None` 时跟随图流的声道数
+    pub layout: Option<ChannelLayout>,
+}
+
+impl PortSpec {
+    pub fn new(name: &str, kind: SignalKind) -> Self {
+        PortSpec { name: name.to_string(), kind, layout: None }
+    }
+
+    /// 在声道数为 `num_channels` 的图流中实际使用的布局，控制与事件端口视为单声道
+    pub fn resolve_layout(&self, num_channels: usize) -> ChannelLayout {
+        match self.kind {
+            SignalKind::Audio => self.layout.unwrap_or(ChannelLayout::from_channels(num_channels)),
+            _ => ChannelLayout::Mono,
+        }
+    }
+}
+
+/// 音频块声明的输入、输出端口，下标即 `Port::port`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortLayout {
+    pub inputs: Vec<PortSpec>,
+    pub outputs: Vec<PortSpec>,
+}
+
+impl PortLayout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 未命名的音频端口，名字为下标
+    pub fn anonymous(port_len: usize) -> Self {
+        let specs = (0..port_len)
+            .map(|port| PortSpec::new(&port.to_string(), SignalKind::Audio))
+            .collect::<Vec<_>>();
+        PortLayout { inputs: specs.clone(), outputs: specs }
+    }
+
+    pub fn input(mut self, name: &str, kind: SignalKind) -> Self {
+        self.inputs.push(PortSpec::new(name, kind));
+        self
+    }
+
+    pub fn output(mut self, name: &str, kind: SignalKind) -> Self {
+        self.outputs.push(PortSpec::new(name, kind));
+        self
+    }
+
+    /// 声明了声道布局的音频输入，连接时图流把上游转换为该布局；声道数不超过 `MAX_FRAME_CHANNELS`
+    pub fn audio_input(mut self, name: &str, layout: ChannelLayout) -> Self {
+        assert!(layout.num_channels() <= MAX_FRAME_CHANNELS, "Port layout has more than {} channels", MAX_FRAME_CHANNELS);
+        self.inputs.push(PortSpec { layout: Some(layout), ..PortSpec::new(name, SignalKind::Audio) });
+        self
+    }
+
+    pub fn audio_output(mut self, name: &str, layout: ChannelLayout) -> Self {
+        assert!(layout.num_channels() <= MAX_FRAME_CHANNELS, "Port layout has more than {} channels", MAX_FRAME_CHANNELS);
+        self.outputs.push(PortSpec { layout: Some(layout), ..PortSpec::new(name, SignalKind::Audio) });
+        self
+    }
+
+    pub fn specs(&self, direction: PortDirection) -> &[PortSpec] {
+        match direction {
+            PortDirection::Input => &self.inputs,
+            PortDirection::Output => &self.outputs,
+        }
+    }
+
+    pub fn find(&self, direction: PortDirection, name: &str) -> Option<usize> {
+        self.specs(direction).iter().position(|spec| spec.name == name)
+    }
+}
+
+/// 兼容旧的 `port_len` 写法
+impl From<usize> for PortLayout {
+    fn from(port_len: usize) -> Self {
+        PortLayout::anonymous(port_len)
+    }
+}
+
+/// 未指定时控制端口每个值覆盖的帧数
+pub const DEFAULT_CONTROL_INTERVAL: usize = 64;
+
+/// 各端口的采样缓冲区，事件端口的事件另外存放在 `events` 中
+///
+/// 音频端口按端口的声道布局分声道存放，每个声道 `num_frames` 个连续的采样，通过 `channel`
+/// 取出，未声明布局时共 `buffer_size` 个；控制端口每 `control_interval` 帧一个值，所有声道
+/// 共用；事件端口不占用采样缓冲区。
 #[derive(Clone)]
-pub struct IOData(Vec<Vec<f32>>);
+pub struct IOData {
+    data: Vec<Vec<f32>>,
+    events: Vec<EventBuffer>,
+    kinds: Vec<SignalKind>,
+    layouts: Vec<ChannelLayout>,
+    buffer_size: usize,
+    num_frames: usize,
+    control_interval: usize,
+}
 
 impl Index<usize> for IOData {
     type Output = Vec<f32>;
 
     fn index(&self, index: usize) -> &Self::Output {
-        &self.0[index]
+        &self.data[index]
     }
 }
 
 impl IndexMut<usize> for IOData {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.0[index]
+        &mut self.data[index]
     }
 }
 
 impl IOData {
     pub fn new(port_len: usize, buffer_size: usize) -> Self {
-        IOData(vec![vec![0.0; buffer_size]; port_len])
+        Self::from_raw(vec![vec![0.0; buffer_size]; port_len])
     }
 
+    /// 所有端口都视为单声道的音频端口
     pub fn from_raw(data: Vec<Vec<f32>>) -> Self {
-        IOData(data)
+        let buffer_size = data.first().map_or(0, |port| port.len());
+        IOData {
+            buffer_size,
+            num_frames: buffer_size,
+            events: vec![EventBuffer::new(); data.len()],
+            kinds: vec![SignalKind::Audio; data.len()],
+            layouts: vec![ChannelLayout::Mono; data.len()],
+            data,
+            control_interval: DEFAULT_CONTROL_INTERVAL,
+        }
     }
 
     pub fn port_len(&self) -> usize {
-        self.0.len()
+        self.data.len()
     }
 
+    /// 音频端口的缓冲区长度，即帧数乘以图流的声道数
     pub fn buffer_size(&self) -> usize {
-        self.0.first().unwrap().len()
+        self.buffer_size
+    }
+
+    /// 本次处理的帧数
+    pub fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    pub fn kind(&self, port: usize) -> SignalKind {
+        self.kinds[port]
+    }
+
+    /// 端口的声道布局，控制与事件端口为单声道
+    pub fn layout(&self, port: usize) -> ChannelLayout {
+        self.layouts[port]
+    }
+
+    /// 音频端口第 `channel` 个声道的采样
+    pub fn channel(&self, port: usize, channel: usize) -> &[f32] {
+        &self.data[port][channel * self.num_frames..(channel + 1) * self.num_frames]
+    }
+
+    pub fn channel_mut(&mut self, port: usize, channel: usize) -> &mut [f32] {
+        &mut self.data[port][channel * self.num_frames..(channel + 1) * self.num_frames]
+    }
+
+    /// 取出音频端口第 `frame` 帧的前 `to.len()` 个声道
+    pub fn read_frame(&self, port: usize, frame: usize, to: &mut [f32]) {
+        for (channel, sample) in to.iter_mut().enumerate() {
+            *sample = self.data[port][channel * self.num_frames + frame];
+        }
+    }
+
+    pub fn write_frame(&mut self, port: usize, frame: usize, from: &[f32]) {
+        for (channel, sample) in from.iter().enumerate() {
+            self.data[port][channel * self.num_frames + frame] = *sample;
+        }
+    }
+
+    /// 音频端口按声道遍历
+    pub fn channels(&self, port: usize) -> std::slice::ChunksExact<'_, f32> {
+        self.data[port].chunks_exact(self.num_frames.max(1))
+    }
+
+    pub fn channels_mut(&mut self, port: usize) -> std::slice::ChunksExactMut<'_, f32> {
+        self.data[port].chunks_exact_mut(self.num_frames.max(1))
+    }
+
+    /// 控制端口每个值覆盖的帧数
+    pub fn control_interval(&self) -> usize {
+        self.control_interval
+    }
+
+    pub fn events(&self, port: usize) -> &EventBuffer {
+        &self.events[port]
+    }
+
+    pub fn events_mut(&mut self, port: usize) -> &mut EventBuffer {
+        &mut self.events[port]
+    }
+
+    /// 在预留的容量内改变缓冲区长度，不超过容量时不会分配
+    pub(crate) fn set_buffer_size(&mut self, buffer_size: usize, num_channels: usize) {
+        let num_frames = buffer_size / num_channels;
+        let control_len = num_frames.div_ceil(self.control_interval);
+        for ((port, kind), layout) in self.data.iter_mut().zip(self.kinds.iter()).zip(self.layouts.iter()) {
+            let len = match kind {
+                SignalKind::Audio => num_frames * layout.num_channels(),
+                SignalKind::Control => control_len,
+                SignalKind::Event => 0,
+            };
+            port.resize(len, 0.0);
+        }
+        self.buffer_size = buffer_size;
+        self.num_frames = num_frames;
+    }
+
+    pub(crate) fn clear_events(&mut self) {
+        self.events.iter_mut().for_each(EventBuffer::clear);
+    }
+
+    /// 按端口声明预先分配，`capacity` 为图流声道数下的采样数，事件端口额外预留 `EVENT_CAPACITY` 个事件
+    pub(crate) fn with_capacity(
+        specs: &[PortSpec],
+        buffer_size: usize,
+        capacity: usize,
+        num_channels: usize,
+        control_interval: usize,
+    ) -> Self {
+        let mut data = IOData {
+            data: specs.iter().map(|spec| match spec.kind {
+                SignalKind::Event => Vec::new(),
+                _ => Vec::with_capacity(capacity / num_channels * spec.resolve_layout(num_channels).num_channels()),
+            }).collect(),
+            events: specs.iter().map(|spec| match spec.kind {
+                SignalKind::Event => EventBuffer::with_capacity(EVENT_CAPACITY),
+                _ => EventBuffer::new(),
+            }).collect(),
+            kinds: specs.iter().map(|spec| spec.kind).collect(),
+            layouts: specs.iter().map(|spec| spec.resolve_layout(num_channels)).collect(),
+            buffer_size: 0,
+            num_frames: 0,
+            control_interval,
+        };
+        data.set_buffer_size(buffer_size, num_channels);
+        data
     }
 }
 
@@ -117,54 +402,182 @@ impl IntoIterator for IOData {
     type IntoIter = std::vec::IntoIter<Self::Item>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        self.data.into_iter()
+    }
+}
+
+/// 单个音频块允许的最大端口数
+pub const MAX_PORT_LEN: usize = 256;
+
+/// 未声明端口的块（闭包）默认的端口数
+pub const DEFAULT_PORT_LEN: usize = 16;
+
+/// 带状态的音频块（振荡器相位、滤波器记忆、延迟线等）
+///
+/// 图流在添加块时、以及采样率或最大缓冲区变化时调用 `prepare`，
+/// 在需要从头播放时调用 `reset`；`process` 每个缓冲区调用一次。
+pub trait Processor: Send {
+    /// `max_buffer` 为最大缓冲区长度，即最大帧数乘以图流的声道数
+    fn prepare(&mut self, _sample_rate: u32, _max_buffer: usize, _num_channels: usize) {}
+
+    /// 控制端口的间隔（帧），每次 `prepare` 之前调用；按控制间隔分配缓冲区的块在这里记下
+    fn set_control_interval(&mut self, _control_interval: usize) {}
+
+    fn reset(&mut self) {}
+
+    /// 声明端口，默认为 `DEFAULT_PORT_LEN` 个未命名的音频端口
+    fn ports(&self) -> PortLayout {
+        PortLayout::anonymous(DEFAULT_PORT_LEN)
+    }
+
+    /// 声明参数，返回与块内 `Param` 共享的句柄
+    fn params(&self) -> Vec<ParamHandle> {
+        Vec::new()
+    }
+
+    /// 处理引入的延迟（帧），例如前瞻限幅器、线性相位 EQ 与 FFT 效果
+    ///
+    /// 图流在编译时读取，为汇合的并行路径插入补偿延迟；在 `prepare` 之后改变时需要调用
+    /// `GraphFlow::refresh_latency`。
+    fn latency(&self) -> usize {
+        0
+    }
+
+    fn process(&mut self, time: Time, inputs: &IOData, outputs: &mut IOData, num_channels: usize);
+}
+
+/// 把无状态的闭包包装为 `Processor`
+struct FnProcessor<F>(F);
+
+impl<F> Processor for FnProcessor<F>
+where
+    F: Fn(Time, &IOData, &mut IOData, usize) + Send + Sync,
+{
+    fn process(&mut self, time: Time, inputs: &IOData, outputs: &mut IOData, num_channels: usize) {
+        (self.0)(time, inputs, outputs, num_channels)
+    }
+}
+
+/// 不加锁的 `Processor` 容器
+///
+/// 调度器保证同一时刻只有一个线程访问：运行时由认领了该节点的线程独占，
+/// 不在运行时由持有 `&mut GraphFlow` 的线程独占。
+pub(crate) struct ProcessorCell(UnsafeCell<Box<dyn Processor>>);
+
+unsafe impl Sync for ProcessorCell {}
+
+impl ProcessorCell {
+    /// # Safety
+    ///
+    /// 调用者必须保证没有其他线程同时访问该 `Processor`
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) unsafe fn get_mut(&self) -> &mut dyn Processor {
+        (*self.0.get()).as_mut()
+    }
+
+    /// # Safety
+    ///
+    /// 调用者必须保证没有其他线程同时修改该 `Processor`
+    pub(crate) unsafe fn get(&self) -> &dyn Processor {
+        (*self.0.get()).as_ref()
     }
 }
 
 pub struct Block {
-    pub(crate) process: Arc<dyn Fn(Time, &IOData, &mut IOData, usize) + Send + Sync>,
-    pub port_len: usize,
+    pub(crate) processor: Arc<ProcessorCell>,
+    pub ports: PortLayout,
+    pub params: Vec<ParamHandle>,
     pub inputs: HashMap<BlockId, HashSet<(usize, usize)>>,  // block_id -> (pre_port, self_port)
-    pub(crate) data: IOData,
+    pub feedback_inputs: HashMap<BlockId, HashSet<(usize, usize)>>,  // 同上，延迟一个缓冲区
     pub(crate) d_in: usize,
-    pub(crate) d_in_cur: usize,
+    /// 由 `Registry::create` 创建时记录类型名与配置，保存工程时使用
+    pub block_type: Option<BlockType>,
+    pub(crate) mode: ModeHandle,
+    pub(crate) stats: Arc<Stats>,
+    /// `Processor::latency` 在添加或 `prepare` 时的值，编译运行计划时使用
+    pub(crate) latency: usize,
 }
 
-unsafe impl Sync for Block {}
-
 impl Block {
-    pub fn new(
+    /// 使用默认的 `DEFAULT_PORT_LEN` 个未命名的音频端口
+    pub fn new(func: impl Fn(Time, &IOData, &mut IOData, usize) + Send + Sync + 'static) -> Self {
+        Self::with_ports(func, DEFAULT_PORT_LEN)
+    }
+
+    /// 使用给定的端口，可以是 `PortLayout` 或未命名音频端口的个数
+    pub fn with_ports(
         func: impl Fn(Time, &IOData, &mut IOData, usize) + Send + Sync + 'static,
-        port_len: usize,
-        buffer_size: usize,
+        ports: impl Into<PortLayout>,
     ) -> Self {
+        Self::from_parts(FnProcessor(func), ports.into())
+    }
+
+    /// 使用 `Processor::ports` 声明的端口
+    pub fn from_processor(processor: impl Processor + 'static) -> Self {
+        let ports = processor.ports();
+        Self::from_parts(processor, ports)
+    }
+
+    fn from_parts(processor: impl Processor + 'static, ports: PortLayout) -> Self {
         Block {
-            process: Arc::new(func),
-            port_len,
+            params: processor.params(),
+            processor: Arc::new(ProcessorCell(UnsafeCell::new(Box::new(processor)))),
+            ports,
             inputs: HashMap::new(),
-            data: IOData::new(port_len, buffer_size),
+            feedback_inputs: HashMap::new(),
             d_in: 0,
-            d_in_cur: 0,
+            block_type: None,
+            mode: ModeHandle::default(),
+            stats: Arc::new(Stats::default()),
+            latency: 0,
         }
     }
+
+    pub fn input_len(&self) -> usize {
+        self.ports.inputs.len()
+    }
+
+    pub fn output_len(&self) -> usize {
+        self.ports.outputs.len()
+    }
 }
 
 pub trait BlockMarker {}
 pub struct WithInput;
 pub struct WithoutInput;
+pub struct Prebuilt;
+pub struct Stateful;
 impl BlockMarker for WithInput {}
 impl BlockMarker for WithoutInput {}
+impl BlockMarker for Prebuilt {}
+impl BlockMarker for Stateful {}
 
 pub trait IntoBlock<M: BlockMarker> {
     fn into_block(self) -> Block;
 }
 
+/// 直接添加由 `Block::with_ports` 构造的块，用于声明端口
+impl IntoBlock<Prebuilt> for Block {
+    fn into_block(self) -> Block {
+        self
+    }
+}
+
+impl<P> IntoBlock<Stateful> for P
+where
+    P: Processor + 'static,
+{
+    fn into_block(self) -> Block {
+        Block::from_processor(self)
+    }
+}
+
 impl<F> IntoBlock<WithInput> for F
 where
     F: Fn(Time, &IOData, &mut IOData, usize) + Send + Sync + 'static,
 {
     fn into_block(self) -> Block {
-        Block::new(self, 256, 512)
+        Block::new(self)
     }
 }
 impl<F> IntoBlock<WithoutInput> for F
@@ -172,10 +585,10 @@ where
     F: Fn(Time, &mut IOData, usize) + Send + Sync + 'static,
 {
     fn into_block(self) -> Block {
-        let func = move |time: Time, inputs: &IOData, outputs: &mut IOData, num_channels: usize| {
+        let func = move |time: Time, _inputs: &IOData, outputs: &mut IOData, num_channels: usize| {
             self(time, outputs, num_channels)
         };
-        Block::new(func, 256, 512)
+        Block::new(func)
     }
 }
 
@@ -184,7 +597,7 @@ where
 macro_rules! frame_block {
     ($block_fn:ident) => {
         |mut time: Time, inputs: &IOData, outputs: &mut IOData, num_channels: usize| {
-            let buffer_size = inputs.buffer_size();
+            let buffer_size = inputs.buffer_size().max(outputs.buffer_size());
             let num_frames = buffer_size / num_channels;
             let port_count = inputs.port_len();
 
@@ -194,20 +607,23 @@ macro_rules! frame_block {
             
             // 按帧处理数据
             for frame_idx in 0..num_frames {
-                let start = frame_idx * num_channels;
-                let end = start + num_channels;
-
-                // 构建当前帧输入 - 直接使用切片引用
+                // 构建当前帧输入 - 从各声道取出这一帧
                 for port_idx in 0..port_count {
-                    frame_inputs[port_idx].copy_from_slice(&inputs[port_idx][start..end]);
+                    let port_channels = inputs.layout(port_idx).num_channels().min(num_channels);
+                    for channel in 0..port_channels {
+                        frame_inputs[port_idx][channel] = inputs.channel(port_idx, channel)[frame_idx];
+                    }
                 }
 
                 // 处理当前帧
                 $block_fn(time, &frame_inputs, &mut frame_outputs, num_channels);
 
-                // 直接写入输出 - 避免中间缓冲
+                // 写回输出的各声道
                 for port_idx in 0..outputs.port_len() {
-                    outputs[port_idx][start..end].copy_from_slice(&frame_outputs[port_idx]);
+                    let port_channels = outputs.layout(port_idx).num_channels().min(num_channels);
+                    for channel in 0..port_channels {
+                        outputs.channel_mut(port_idx, channel)[frame_idx] = frame_outputs[port_idx][channel];
+                    }
                     frame_outputs[port_idx].fill(0.0); // 重置输出缓冲区
                 }
 
@@ -0,0 +1,225 @@
+//! 声道布局、声道转换与声像
+//!
+//! 音频端口可以声明自己的声道布局，未声明时跟随图流的声道数。连接两端布局不同时，
+//! 图流在混入输入时自动转换：单声道复制到各声道（5.1 只送中置），多声道到单声道取平均，
+//! 5.1 到立体声按 ITU 系数下混。端口的采样按声道分开存放，块通过 `IOData::channel`
+//! 逐声道处理，只有宿主的输出与探针按声道交错排列。
+
+use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_4};
+
+use crate::block::{IOData, PortLayout, Processor, Time};
+use crate::param::{Param, ParamHandle, ParamSpec};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+    /// 声道顺序为 L, R, C, LFE, Ls, Rs
+    Surround51,
+    /// 没有约定含义的 N 个声道
+    Discrete(usize),
+}
+
+impl ChannelLayout {
+    /// 按声道数选择常见的布局
+    pub fn from_channels(num_channels: usize) -> Self {
+        match num_channels {
+            1 => ChannelLayout::Mono,
+            2 => ChannelLayout::Stereo,
+            6 => ChannelLayout::Surround51,
+            n => ChannelLayout::Discrete(n),
+        }
+    }
+
+    pub fn num_channels(self) -> usize {
+        match self {
+            ChannelLayout::Mono => 1,
+            ChannelLayout::Stereo => 2,
+            ChannelLayout::Surround51 => 6,
+            ChannelLayout::Discrete(n) => n,
+        }
+    }
+}
+
+/// 端口布局支持的最大声道数，逐帧转换声道时在栈上取出一帧
+pub const MAX_FRAME_CHANNELS: usize = 64;
+
+/// 把一帧从 `from_layout` 转换到 `to_layout`，乘以 `gain` 后叠加到 `to`
+pub fn mix_frame(from: &[f32], from_layout: ChannelLayout, to: &mut [f32], to_layout: ChannelLayout, gain: f32) {
+    use ChannelLayout::*;
+
+    match (from_layout, to_layout) {
+        _ if from.len() == to.len() => {
+            to.iter_mut().zip(from.iter()).for_each(|(to, from)| *to += *from * gain);
+        }
+        (Mono, Surround51) => to[2] += from[0] * gain,
+        (Mono, _) => to.iter_mut().for_each(|to| *to += from[0] * gain),
+        (Surround51, Stereo | Mono) => {
+            let left = from[0] + FRAC_1_SQRT_2 * (from[2] + from[4]);
+            let right = from[1] + FRAC_1_SQRT_2 * (from[2] + from[5]);
+            if to_layout == Mono {
+                to[0] += (left + right) * 0.5 * gain;
+            } else {
+                to[0] += left * gain;
+                to[1] += right * gain;
+            }
+        }
+        (_, Mono) => to[0] += from.iter().sum::<f32>() / from.len() as f32 * gain,
+        // 其余情况按顺序对应，多出的声道丢弃或留空
+        _ => to.iter_mut().zip(from.iter()).for_each(|(to, from)| *to += *from * gain),
+    }
+}
+
+/// 逐帧把 `from` 的音频端口转换到 `to` 的布局，乘以每帧的 `gain` 后叠加
+pub(crate) fn mix_channels(from: &IOData, from_port: usize, to: &mut IOData, to_port: usize, mut gain: impl FnMut() -> f32) {
+    let (from_layout, to_layout) = (from.layout(from_port), to.layout(to_port));
+    let (from_frame, to_frame) = (&mut [0.0; MAX_FRAME_CHANNELS], &mut [0.0; MAX_FRAME_CHANNELS]);
+    let (from_frame, to_frame) = (&mut from_frame[..from_layout.num_channels()], &mut to_frame[..to_layout.num_channels()]);
+    for frame in 0..to.num_frames().min(from.num_frames()) {
+        from.read_frame(from_port, frame, from_frame);
+        to.read_frame(to_port, frame, to_frame);
+        mix_frame(from_frame, from_layout, to_frame, to_layout, gain());
+        to.write_frame(to_port, frame, to_frame);
+    }
+}
+
+/// 单声道信号在立体声中的声像定律，`pan` 从 -1（左）到 1（右）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PanLaw {
+    /// 中间 -6 dB，左右增益之和不变
+    Linear,
+    /// 中间 -3 dB，功率不变
+    #[default]
+    ConstantPower,
+    /// 中间 -4.5 dB，前两者的折中
+    Compromise,
+}
+
+impl PanLaw {
+    /// 左、右声道的增益
+    pub fn gains(self, pan: f32) -> (f32, f32) {
+        let pan = pan.clamp(-1.0, 1.0);
+        let linear = ((1.0 - pan) * 0.5, (1.0 + pan) * 0.5);
+        let angle = (pan + 1.0) * FRAC_PI_4;
+        let power = (angle.cos(), angle.sin());
+        match self {
+            PanLaw::Linear => linear,
+            PanLaw::ConstantPower => power,
+            PanLaw::Compromise => ((linear.0 * power.0).sqrt(), (linear.1 * power.1).sqrt()),
+        }
+    }
+}
+
+/// 声像块：单声道输入 `in`，立体声输出 `out`，参数 `pan`
+///
+/// 上游不是单声道时先由图流下混。
+pub struct Pan {
+    law: PanLaw,
+    pan: Param,
+}
+
+impl Pan {
+    pub fn new(law: PanLaw) -> Self {
+        Pan { law, pan: Param::new(ParamSpec::new("pan", -1.0, 1.0, 0.0)) }
+    }
+}
+
+impl Processor for Pan {
+    fn prepare(&mut self, sample_rate: u32, _max_buffer: usize, _num_channels: usize) {
+        self.pan.prepare(sample_rate);
+    }
+
+    fn reset(&mut self) {
+        self.pan.reset();
+    }
+
+    fn ports(&self) -> PortLayout {
+        PortLayout::new()
+            .audio_input("in", ChannelLayout::Mono)
+            .audio_output("out", ChannelLayout::Stereo)
+    }
+
+    fn params(&self) -> Vec<ParamHandle> {
+        vec![self.pan.handle()]
+    }
+
+    fn process(&mut self, _time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+        for frame in 0..outputs.num_frames() {
+            let (left, right) = self.law.gains(self.pan.tick());
+            outputs.channel_mut(0, 0)[frame] = inputs[0][frame] * left;
+            outputs.channel_mut(0, 1)[frame] = inputs[0][frame] * right;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::approx_eq;
+    use crate::block::Block;
+    use crate::graph_flow::GraphFlowBuilder;
+
+    #[test]
+    fn pan_laws() {
+        let (left, right) = PanLaw::ConstantPower.gains(0.0);
+        assert!(approx_eq(left, FRAC_1_SQRT_2) && approx_eq(right, FRAC_1_SQRT_2));
+        assert_eq!(PanLaw::Linear.gains(0.0), (0.5, 0.5));
+        assert!(approx_eq(PanLaw::Compromise.gains(0.0).0, 0.5f32.sqrt() * FRAC_1_SQRT_2.sqrt()));
+        let (left, right) = PanLaw::ConstantPower.gains(1.0);
+        assert!(approx_eq(left, 0.0) && approx_eq(right, 1.0));
+    }
+
+    /// 取出立体声输入的右声道
+    struct Right;
+
+    impl Processor for Right {
+        fn ports(&self) -> PortLayout {
+            PortLayout::new()
+                .audio_input("in", ChannelLayout::Stereo)
+                .audio_output("out", ChannelLayout::Mono)
+        }
+
+        fn process(&mut self, _time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+            outputs[0].copy_from_slice(inputs.channel(0, 1));
+        }
+    }
+
+    #[test]
+    fn channel_conversion() {
+        let mut gf = GraphFlowBuilder { buffer_size: 8, num_channels: 2, num_threads: 0, ..Default::default() }.build();
+        // 单声道合成器混入立体声输出
+        let synth = gf.add_block(Block::with_ports(
+            |_time: Time, _inputs: &IOData, outputs: &mut IOData, _num_channels: usize| outputs[0].fill(0.25),
+            PortLayout::new().audio_output("out", ChannelLayout::Mono),
+        )).unwrap();
+        gf.to_output(synth.named("out")).unwrap();
+        let mut output = vec![0.0; 8];
+        gf.run(8, &mut output);
+        assert!(output.iter().all(|&sample| sample == 0.25));
+
+        // 立体声下混为单声道，声像到右边，再取出右声道
+        let source = gf.add_block(|_time: Time, outputs: &mut IOData, _num_channels: usize| {
+            outputs.channel_mut(0, 0).fill(1.0);
+            outputs.channel_mut(0, 1).fill(0.0);
+        }).unwrap();
+        let pan = gf.add_block(Pan::new(PanLaw::ConstantPower)).unwrap();
+        let right = gf.add_block(Right).unwrap();
+        gf.connect(source.port(0), pan.named("in")).unwrap();
+        gf.connect(pan.named("out"), right.named("in")).unwrap();
+        gf.to_output(right.named("out")).unwrap();
+        gf.param(&pan, "pan").unwrap().set(1.0);
+        gf.reset();
+        let mut tap = gf.tap(pan.named("out")).unwrap();
+        output.fill(0.0);
+        gf.run(8, &mut output);
+        let mut panned = vec![0.0; 8];
+        assert_eq!(tap.read(&mut panned), 8);
+        assert!(panned.chunks(2).all(|frame| approx_eq(frame[0], 0.0) && approx_eq(frame[1], 0.5)));
+        assert!(output.iter().all(|&sample| approx_eq(sample, 0.75)));
+
+        let surround = [1.0, 0.0, 1.0, 1.0, 0.0, 0.0];
+        let mut stereo = [0.0; 2];
+        mix_frame(&surround, ChannelLayout::Surround51, &mut stereo, ChannelLayout::Stereo, 1.0);
+        assert!(approx_eq(stereo[0], 1.0 + FRAC_1_SQRT_2) && approx_eq(stereo[1], FRAC_1_SQRT_2));
+    }
+}
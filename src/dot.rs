@@ -0,0 +1,128 @@
+//! 导出 Graphviz DOT，用于查看图流实际的连接
+//!
+//! 每个块画成一个 record 节点，左列为输入端口，右列为输出端口；反馈连接为虚线，
+//! 混入输出的端口连到 `output` 节点。匿名端口（名字就是下标）只画出有连接的那些。
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::block::{BlockId, Port, PortDirection, SignalKind};
+use crate::graph_flow::{EdgeKind, GraphFlow};
+
+impl GraphFlow {
+    pub fn to_dot(&self) -> String {
+        let edges = self.edges();
+        let outputs = self.outputs();
+        let mut used = HashSet::new();
+        for edge in &edges {
+            used.insert((edge.from, PortDirection::Output));
+            used.insert((edge.to, PortDirection::Input));
+        }
+        used.extend(outputs.iter().map(|port| (*port, PortDirection::Output)));
+
+        let mut dot = String::from("digraph GraphFlow {\n    rankdir=LR;\n    node [shape=record];\n");
+        for block_id in self.block_ids() {
+            let ports = self.ports(&block_id).unwrap();
+            let column = |direction: PortDirection, prefix: char| {
+                ports.specs(direction).iter().enumerate()
+                    .filter(|(index, spec)| {
+                        spec.name != index.to_string() || used.contains(&(block_id.port(*index), direction))
+                    })
+                    .map(|(index, spec)| {
+                        let kind = match spec.kind {
+                            SignalKind::Audio => "",
+                            SignalKind::Control => " (control)",
+                            SignalKind::Event => " (event)",
+                        };
+                        format!("<{}{}> {}{}", prefix, index, escape(&spec.name), kind)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("|")
+            };
+            let _ = writeln!(
+                dot, "    \"{}\" [label=\"{{{{{}}}|{}|{{{}}}}}\"];",
+                node_name(&block_id),
+                column(PortDirection::Input, 'i'),
+                self.block_label(&block_id),
+                column(PortDirection::Output, 'o'),
+            );
+        }
+
+        for edge in &edges {
+            let style = match edge.kind {
+                EdgeKind::Normal => "",
+                EdgeKind::Feedback => " [style=dashed, constraint=false]",
+            };
+            let _ = writeln!(dot, "    {} -> {}{};", endpoint(edge.from, 'o'), endpoint(edge.to, 'i'), style);
+        }
+
+        if !outputs.is_empty() {
+            dot.push_str("    \"output\" [shape=doublecircle];\n");
+            let mut outputs = outputs.iter().map(|port| endpoint(*port, 'o')).collect::<Vec<_>>();
+            outputs.sort();
+            for output in outputs {
+                let _ = writeln!(dot, "    {} -> \"output\";", output);
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// 注册表中的类型名，加上 id 的前 8 位用于区分同类型的块
+    fn block_label(&self, block_id: &BlockId) -> String {
+        let name = self.get_block(block_id).ok()
+            .and_then(|block| block.block_type.as_ref())
+            .map_or("block", |block_type| block_type.name.as_str());
+        format!("{}\\n{}", escape(name), &node_name(block_id)[..8])
+    }
+}
+
+fn node_name(block_id: &BlockId) -> String {
+    block_id.to_string()
+}
+
+fn endpoint(port: Port, prefix: char) -> String {
+    format!("\"{}\":{}{}", node_name(&port.block_id), prefix, port.port)
+}
+
+/// 转义 record 标签中的特殊字符
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '{' | '}' | '|' | '<' | '>' | '"' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, IOData, PortLayout, Time};
+    use crate::graph_flow::GraphFlowBuilder;
+
+    #[test]
+    fn dot_export() {
+        let mut gf = GraphFlowBuilder { num_threads: 0, ..Default::default() }.build();
+        let layout = PortLayout::new()
+            .input("in", SignalKind::Audio)
+            .input("cutoff", SignalKind::Control)
+            .output("out|1", SignalKind::Audio);
+        let filter = gf.add_block(Block::with_ports(|_time: Time, _inputs: &IOData, _outputs: &mut IOData, _num_channels: usize| {}, layout)).unwrap();
+        let source = gf.add_block(|_time: Time, _outputs: &mut IOData, _num_channels: usize| {}).unwrap();
+        gf.connect(source.port(2), filter.named("in")).unwrap();
+        gf.connect_feedback(filter.port(0), filter.named("cutoff")).unwrap();
+        gf.to_output(filter.port(0)).unwrap();
+
+        let dot = gf.to_dot();
+        let (filter, source) = (node_name(&filter), node_name(&source));
+        assert!(dot.contains(&format!("\"{}\" [label=\"{{{{<i0> in|<i1> cutoff (control)}}|block\\n{}|{{<o0> out\\|1}}}}\"];", filter, &filter[..8])));
+        // 匿名端口只画出有连接的
+        assert!(dot.contains(&format!("\"{}\" [label=\"{{{{}}|block\\n{}|{{<o2> 2}}}}\"];", source, &source[..8])));
+        assert!(dot.contains(&format!("\"{}\":o2 -> \"{}\":i0;", source, filter)));
+        assert!(dot.contains(&format!("\"{}\":o0 -> \"{}\":i1 [style=dashed, constraint=false];", filter, filter)));
+        assert!(dot.contains(&format!("\"{}\":o0 -> \"output\";", filter)));
+    }
+}
@@ -0,0 +1,250 @@
+//! 拆分：可编辑的图流与在音频线程上运行的引擎
+//!
+//! `GraphFlow::split` 把运行部分交给 `Engine`，放进声卡回调；`GraphFlow` 留在 UI 线程上继续
+//! 编辑。之后每次结构变化都在编辑线程上编译新的运行计划，通过 `Exchange` 交给引擎，
+//! 引擎从不等待锁。块在新旧计划之间共享，不能在同一个缓冲区里运行两次，所以交叉淡化放在
+//! 新计划里完成（见 `Base::Running`）：引擎在缓冲区边界换上新计划并交接块的状态，
+//! 新的连接与输出在 `SWITCH_FADE_SECS` 内淡入，断开的连接与删除的块同时淡出。
+//! 换下的计划送回编辑线程释放。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::block::Time;
+use crate::profile::GraphStats;
+use crate::schedule::{OutputQueue, Plan, ThreadPool};
+use crate::tap::Taps;
+
+/// 引擎与编辑线程各自最多暂存的待释放计划数，暂存满时新计划留到下一个缓冲区再换
+const RETIRED_CAPACITY: usize = 8;
+
+/// 编辑线程与引擎之间交换运行计划
+pub(crate) struct Exchange {
+    next: Mutex<Option<Arc<Plan>>>,
+    /// 容量固定，引擎只在有空位时放入
+    retired: Mutex<Vec<Arc<Plan>>>,
+    reset: AtomicBool,
+}
+
+impl Exchange {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Exchange {
+            next: Mutex::new(None),
+            retired: Mutex::new(Vec::with_capacity(RETIRED_CAPACITY)),
+            reset: AtomicBool::new(false),
+        })
+    }
+
+    /// 交出 `compile` 编译的新计划，顺便释放引擎换下的计划
+    ///
+    /// `compile` 的参数表示上一份计划是否已被引擎取走：没取走的直接作废，新计划应当仍然相对
+    /// 引擎正在运行的计划淡化。编译期间引擎取不到锁，新计划留到下一个缓冲区再换。
+    pub(crate) fn publish(&self, compile: impl FnOnce(bool) -> Arc<Plan>) {
+        let mut next = self.next.lock().unwrap();
+        let taken = next.take().is_none();
+        *next = Some(compile(taken));
+        drop(next);
+        self.retired.lock().unwrap().clear();
+    }
+
+    /// 请求引擎在下一个缓冲区开始前回到时间零点
+    pub(crate) fn request_reset(&self) {
+        self.reset.store(true, Ordering::Release);
+    }
+}
+
+/// 在音频线程上运行图流，由 `GraphFlow::split` 创建
+pub struct Engine {
+    plan: Arc<Plan>,
+    /// 已经取到、还没换上的计划
+    next: Option<Arc<Plan>>,
+    thread_pool: ThreadPool,
+    exchange: Arc<Exchange>,
+    /// 还没送回编辑线程的旧计划，容量固定
+    retiring: Vec<Arc<Plan>>,
+    time: Time,
+    buffer_size: u32,
+    num_channels: usize,
+    queue: OutputQueue,
+    stats: Arc<GraphStats>,
+    taps: Taps,
+}
+
+impl Engine {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        plan: Arc<Plan>,
+        thread_pool: ThreadPool,
+        exchange: Arc<Exchange>,
+        time: Time,
+        buffer_size: u32,
+        num_channels: usize,
+        queue: OutputQueue,
+        stats: Arc<GraphStats>,
+        taps: Taps,
+    ) -> Self {
+        Engine {
+            plan,
+            next: None,
+            thread_pool,
+            exchange,
+            retiring: Vec::with_capacity(RETIRED_CAPACITY),
+            time,
+            buffer_size,
+            num_channels,
+            queue,
+            stats,
+            taps,
+        }
+    }
+
+    pub fn time(&self) -> Time {
+        self.time
+    }
+
+    /// 已经取到新计划，但工作线程正占用线程池或待释放的计划太多，还没换上
+    pub fn is_swapping(&self) -> bool {
+        self.next.is_some()
+    }
+
+    /// 与 `GraphFlow::run` 相同：填满 `output[..buffer_size]`，长度任意
+    pub fn run(&mut self, buffer_size: u32, output: &mut [f32]) {
+        // 重置时队列中还没交出去的输出一起丢弃
+        if self.exchange.reset.swap(false, Ordering::AcqRel) {
+            self.time = Time::new(self.time.sample_rate());
+            self.plan.reset();
+            self.queue.clear();
+        }
+        let len = output.len().min(buffer_size as usize);
+        let mut queue = std::mem::take(&mut self.queue);
+        queue.fill(&mut output[..len], |block| self.run_block(block));
+        self.queue = queue;
+    }
+
+    fn run_block(&mut self, output: &mut [f32]) {
+        self.receive();
+        self.plan.process(self.time, self.buffer_size as usize, &self.thread_pool, &self.stats, &self.taps);
+        output.fill(0.0);
+        self.plan.mix_outputs(output);
+        self.time.tick_by_buffer(self.buffer_size as u64, self.num_channels);
+    }
+
+    /// 送回旧计划、换上新计划；锁被占用或暂存已满时留到下一个缓冲区
+    fn receive(&mut self) {
+        if !self.retiring.is_empty() {
+            if let Ok(mut retired) = self.exchange.retired.try_lock() {
+                let room = RETIRED_CAPACITY.saturating_sub(retired.len()).min(self.retiring.len());
+                retired.extend(self.retiring.drain(..room));
+            }
+        }
+        if self.next.is_none() {
+            if let Ok(mut next) = self.exchange.next.try_lock() {
+                self.next = next.take();
+            }
+        }
+        if let Some(next) = self.next.take() {
+            if self.retiring.len() < RETIRED_CAPACITY && self.thread_pool.try_set_plan(&next) {
+                next.take_over(&self.plan);
+                let old = std::mem::replace(&mut self.plan, next);
+                self.retiring.push(old);
+            } else {
+                self.next = Some(next);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::approx_eq;
+    use crate::graph_flow::GraphFlowBuilder;
+    use crate::test_util::{constant, pass};
+
+    #[test]
+    fn hot_swap() {
+        // 淡化 10 帧，每个缓冲区 10 帧
+        let mut gf = GraphFlowBuilder { sample_rate: 1000, buffer_size: 10, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let a = gf.add_block(constant).unwrap();
+        gf.to_output(a.port(0)).unwrap();
+        let mut engine = gf.split();
+        assert!(gf.is_split());
+
+        // 引擎可以交给另一个线程
+        let mut output = vec![0.0; 20];
+        std::thread::scope(|scope| {
+            scope.spawn(|| engine.run(10, &mut output[..10]));
+        });
+        assert!(output[..10].iter().all(|&sample| sample == 1.0));
+
+        // 播放中编辑：新的输出淡入，原来的输出不中断
+        let b = gf.add_block(constant).unwrap();
+        gf.to_output(b.port(0)).unwrap();
+        engine.run(20, &mut output);
+        assert!(output[..10].windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(output[0] > 1.0 && output[10..].iter().all(|&sample| sample == 2.0));
+        assert!(!engine.is_swapping());
+
+        // 删除的块淡出
+        assert!(gf.remove_block(a));
+        engine.run(20, &mut output);
+        assert!(output[..10].windows(2).all(|pair| pair[0] >= pair[1]));
+        assert!(output[0] > 1.5 && output[9..].iter().all(|&sample| sample == 1.0));
+
+        // 同一个信号换一条路径输出时音量不变，断开的连接淡出
+        let c = gf.add_block(pass).unwrap();
+        gf.begin_transaction();
+        assert!(gf.remove_output(b.port(0)));
+        gf.connect(b.port(0), c.port(0)).unwrap();
+        gf.to_output(c.port(0)).unwrap();
+        gf.end_transaction();
+        engine.run(20, &mut output);
+        assert!(output.iter().all(|&sample| approx_eq(sample, 1.0)));
+        assert!(gf.disconnect(b.port(0), c.port(0)));
+        engine.run(20, &mut output);
+        assert!(output[..10].windows(2).all(|pair| pair[0] > pair[1]));
+        assert!(output[0] > 0.5 && output[9..].iter().all(|&sample| sample == 0.0));
+
+        // 重置时丢弃队列中剩下的输出，从时间零点重新处理
+        engine.run(5, &mut output[..5]);
+        gf.reset();
+        engine.run(5, &mut output[..5]);
+        assert_eq!(engine.time().sample(), 10);
+    }
+
+    #[test]
+    fn edits_within_one_fade() {
+        // 淡化 10 帧，每个缓冲区 4 帧
+        let mut gf = GraphFlowBuilder { sample_rate: 1000, buffer_size: 4, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let a = gf.add_block(constant).unwrap();
+        gf.to_output(a.port(0)).unwrap();
+        let mut engine = gf.split();
+        let mut output = vec![0.0; 12];
+        engine.run(4, &mut output[..4]);
+
+        // 删除的块淡出到一半时又有新的修改，淡出从当前的电平继续
+        assert!(gf.remove_block(a));
+        engine.run(4, &mut output[..4]);
+        let last = output[3];
+        assert!(last > 0.0 && last < 1.0);
+        let b = gf.add_block(pass).unwrap();
+        gf.to_output(b.port(0)).unwrap();
+        engine.run(12, &mut output);
+        assert!(output[0] < last && last - output[0] < 0.15);
+        assert!(output.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert!(output[11] == 0.0);
+
+        // 删除后立即撤销，块从当前的电平淡入
+        assert!(gf.remove_output(b.port(0)));
+        let c = gf.add_block(constant).unwrap();
+        gf.to_output(c.port(0)).unwrap();
+        engine.run(4, &mut output[..4]);
+        assert!(gf.remove_block(c));
+        engine.run(4, &mut output[..4]);
+        let last = output[3];
+        assert_eq!(gf.undo(), Ok(true));
+        engine.run(12, &mut output);
+        assert!(output[0] > last && output[0] - last < 0.2);
+        assert!(output.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(output[11] == 1.0);
+    }
+}
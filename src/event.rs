@@ -0,0 +1,252 @@
+//! 事件端口上传递的带时间戳的事件
+//!
+//! 每个缓冲区内的事件按帧偏移排序，偏移以帧（而不是交错的采样）为单位。
+
+use crate::block::{IOData, PortLayout, Processor, SignalKind, Time};
+
+/// 运行计划为每个事件端口预留的事件数，超过时才会在音频线程上分配
+pub const EVENT_CAPACITY: usize = 1024;
+
+const NOTE_OFF_MSG: u8 = 0x80;
+const NOTE_ON_MSG: u8 = 0x90;
+const CONTROL_CHANGE_MSG: u8 = 0xB0;
+const PITCH_BEND_MSG: u8 = 0xE0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOn { channel: u8, key: u8, velocity: u8 },
+    NoteOff { channel: u8, key: u8, velocity: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    /// `value` 在 `-8192..=8191` 内，0 为不弯音
+    PitchBend { channel: u8, value: i16 },
+}
+
+impl MidiMessage {
+    /// 解析原始 MIDI 消息，力度为 0 的 NOTE ON 视为 NOTE OFF
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&status, data) = bytes.split_first()?;
+        let channel = status & 0x0F;
+        let (&a, &b) = (data.first()?, data.get(1)?);
+        let message = match status & 0xF0 {
+            NOTE_ON_MSG if b > 0 => MidiMessage::NoteOn { channel, key: a, velocity: b },
+            NOTE_ON_MSG | NOTE_OFF_MSG => MidiMessage::NoteOff { channel, key: a, velocity: b },
+            CONTROL_CHANGE_MSG => MidiMessage::ControlChange { channel, controller: a, value: b },
+            PITCH_BEND_MSG => MidiMessage::PitchBend {
+                channel,
+                value: (((b as i16 & 0x7F) << 7) | (a as i16 & 0x7F)) - 8192,
+            },
+            _ => return None,
+        };
+        Some(message)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 3] {
+        match *self {
+            MidiMessage::NoteOn { channel, key, velocity } => [NOTE_ON_MSG | channel, key, velocity],
+            MidiMessage::NoteOff { channel, key, velocity } => [NOTE_OFF_MSG | channel, key, velocity],
+            MidiMessage::ControlChange { channel, controller, value } => [CONTROL_CHANGE_MSG | channel, controller, value],
+            MidiMessage::PitchBend { channel, value } => {
+                let value = (value.clamp(-8192, 8191) + 8192) as u16;
+                [PITCH_BEND_MSG | channel, (value & 0x7F) as u8, (value >> 7) as u8]
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// 在当前缓冲区内的帧偏移
+    pub frame: usize,
+    pub message: MidiMessage,
+}
+
+/// 一个事件端口在一个缓冲区内的事件，始终按帧偏移排序，同一帧内保持插入顺序
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventBuffer(Vec<Event>);
+
+impl EventBuffer {
+    pub fn new() -> Self {
+        EventBuffer(Vec::new())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        EventBuffer(Vec::with_capacity(capacity))
+    }
+
+    pub fn push(&mut self, event: Event) {
+        let index = self.0.partition_point(|e| e.frame <= event.frame);
+        self.0.insert(index, event);
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Event> {
+        self.0.iter()
+    }
+
+    /// 落在第 `frame` 帧上的事件
+    pub fn at_frame(&self, frame: usize) -> impl Iterator<Item = &Event> {
+        let start = self.0.partition_point(|e| e.frame < frame);
+        self.0[start..].iter().take_while(move |e| e.frame == frame)
+    }
+
+    /// 把帧偏移小于 `frames` 的事件移到 `to`，其余事件的偏移减去 `frames`
+    pub(crate) fn drain_before(&mut self, frames: usize, to: &mut EventBuffer) {
+        let count = self.0.partition_point(|e| e.frame < frames);
+        for event in self.0.drain(..count) {
+            to.push(event);
+        }
+        self.0.iter_mut().for_each(|e| e.frame -= frames);
+    }
+
+    /// 合并另一路事件，用于多条连接汇入同一个端口
+    pub(crate) fn merge(&mut self, other: &EventBuffer) {
+        for event in other.iter() {
+            self.push(*event);
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a EventBuffer {
+    type Item = &'a Event;
+    type IntoIter = std::slice::Iter<'a, Event>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// 按拍子循环播放的音序器，从事件端口 `events` 输出
+///
+/// `notes` 中的时间以帧为单位，`length` 为循环长度（帧）。
+pub struct Sequencer {
+    pub notes: Vec<(u64, MidiMessage)>,
+    pub length: u64,
+    position: u64,
+}
+
+impl Sequencer {
+    pub fn new(mut notes: Vec<(u64, MidiMessage)>, length: u64) -> Self {
+        assert!(length > 0, "Sequencer length must be greater than 0");
+        notes.sort_by_key(|(frame, _)| *frame);
+        Sequencer { notes, length, position: 0 }
+    }
+}
+
+impl Processor for Sequencer {
+    fn reset(&mut self) {
+        self.position = 0;
+    }
+
+    fn ports(&self) -> PortLayout {
+        PortLayout::new().output("events", SignalKind::Event)
+    }
+
+    fn process(&mut self, _time: Time, _inputs: &IOData, outputs: &mut IOData, num_channels: usize) {
+        let num_frames = outputs.buffer_size() / num_channels;
+        let events = outputs.events_mut(0);
+        for frame in 0..num_frames {
+            let position = self.position % self.length;
+            let start = self.notes.partition_point(|(note_frame, _)| *note_frame < position);
+            for (_, message) in self.notes[start..].iter().take_while(|(note_frame, _)| *note_frame == position) {
+                events.push(Event { frame, message: *message });
+            }
+            self.position += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph_flow::GraphFlowBuilder;
+
+    #[test]
+    fn midi_round_trip() {
+        for message in [
+            MidiMessage::NoteOn { channel: 1, key: 60, velocity: 100 },
+            MidiMessage::NoteOff { channel: 0, key: 60, velocity: 0 },
+            MidiMessage::ControlChange { channel: 15, controller: 7, value: 127 },
+            MidiMessage::PitchBend { channel: 2, value: -8192 },
+            MidiMessage::PitchBend { channel: 2, value: 8191 },
+        ] {
+            assert_eq!(MidiMessage::from_bytes(&message.to_bytes()), Some(message));
+        }
+        assert_eq!(
+            MidiMessage::from_bytes(&[0x90, 60, 0]),
+            Some(MidiMessage::NoteOff { channel: 0, key: 60, velocity: 0 }),
+        );
+        assert_eq!(MidiMessage::from_bytes(&[0x90, 60]), None);
+    }
+
+    /// 收到 NOTE ON 后输出 1，收到 NOTE OFF 后输出 0
+    struct Gate(f32);
+
+    impl Processor for Gate {
+        fn ports(&self) -> PortLayout {
+            PortLayout::new().input("midi", SignalKind::Event).output("out", SignalKind::Audio)
+        }
+
+        fn process(&mut self, _time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+            for frame in 0..outputs.num_frames() {
+                for event in inputs.events(0).at_frame(frame) {
+                    self.0 = match event.message {
+                        MidiMessage::NoteOn { .. } => 1.0,
+                        _ => 0.0,
+                    };
+                }
+                outputs.channels_mut(0).for_each(|samples| samples[frame] = self.0);
+            }
+        }
+    }
+
+    #[test]
+    fn sample_accurate_events() {
+        let mut gf = GraphFlowBuilder { buffer_size: 16, num_channels: 2, num_threads: 0, ..Default::default() }.build();
+        let sequencer = gf.add_block(Sequencer::new(vec![
+            (3, MidiMessage::NoteOn { channel: 0, key: 60, velocity: 100 }),
+            (10, MidiMessage::NoteOff { channel: 0, key: 60, velocity: 0 }),
+        ], 12)).unwrap();
+        let gate = gf.add_block(Gate(0.0)).unwrap();
+        gf.connect(sequencer.named("events"), gate.named("midi")).unwrap();
+        gf.to_output(gate.named("out")).unwrap();
+        assert!(gf.to_output(sequencer.named("events")).is_err());
+        assert!(gf.connect(sequencer.named("events"), gate.port(1)).is_err());
+
+        // 每个缓冲区 8 帧，循环 12 帧
+        let mut frames = Vec::new();
+        for _ in 0..3 {
+            let mut output = vec![0.0; 16];
+            gf.run(16, &mut output);
+            frames.extend(output.chunks(2).map(|frame| frame[0]));
+        }
+        let expected = (0..24).map(|frame| if (3..10).contains(&(frame % 12)) { 1.0 } else { 0.0 }).collect::<Vec<_>>();
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn events_stay_sorted() {
+        let note = |key| MidiMessage::NoteOn { channel: 0, key, velocity: 100 };
+        let mut events = EventBuffer::new();
+        events.push(Event { frame: 5, message: note(1) });
+        events.push(Event { frame: 2, message: note(2) });
+        events.push(Event { frame: 5, message: note(3) });
+        let keys = events.iter().map(|e| match e.message {
+            MidiMessage::NoteOn { key, .. } => key,
+            _ => unreachable!(),
+        }).collect::<Vec<_>>();
+        assert_eq!(keys, [2, 1, 3]);
+        assert_eq!(events.at_frame(5).count(), 2);
+        assert_eq!(events.at_frame(3).count(), 0);
+    }
+}
@@ -1,30 +1,31 @@
 use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc;
-use std::thread;
+use std::sync::Arc;
 
 use petgraph::graph::DiGraph;
 use petgraph::algo::toposort;
-use uuid::Uuid;
+use petgraph::visit::{EdgeFiltered, EdgeRef};
+use serde::{Deserialize, Serialize};
 
 use crate::block::*;
-
-#[derive(Clone)]
-pub struct Listener {
-    id: BlockId,
-    sender: mpsc::Sender<f32>,
-}
-
-impl Listener {
-    pub fn send(&self, data: f32) {
-        self.sender.send(data).unwrap();
-    }
-}
+use crate::channel::MAX_FRAME_CHANNELS;
+use crate::engine::{Engine, Exchange};
+use crate::history::{Command, History};
+use crate::param::ParamHandle;
+use crate::profile::{GraphStats, Profile};
+use crate::schedule::{Base, DelayLine, Format, OutputQueue, Plan, ThreadPool};
+use crate::switch::{BlockMode, ModeHandle, SoloHandle};
+use crate::tap::{Ring, Tap, Taps, DEFAULT_TAP_CAPACITY};
 
 pub struct  GraphFlowBuilder {
     pub sample_rate: u32,
+    /// 内部固定的处理块长度（交错排列的采样数），与宿主每次回调的长度无关
     pub buffer_size: u32,
     pub num_channels: usize,
+    /// 工作线程数，音频线程本身也参与处理。
+    /// 为 0 时所有块在音频线程上按拓扑序依次处理，每次运行的顺序都相同
+    pub num_threads: usize,
+    /// 控制端口每个值覆盖的帧数，不小于缓冲区帧数时每个缓冲区只有一个值
+    pub control_interval: usize,
 }
 
 impl Default for GraphFlowBuilder {
@@ -33,300 +34,1227 @@ impl Default for GraphFlowBuilder {
             sample_rate: 48000,
             buffer_size: 512,
             num_channels: 2,
+            num_threads: std::thread::available_parallelism().map_or(0, |n| n.get() - 1),
+            control_interval: DEFAULT_CONTROL_INTERVAL,
         }
     }
 }
 
 impl GraphFlowBuilder {
     pub fn build(&self) -> GraphFlow {
-        let (result_tx, result_rx) = mpsc::channel();
+        assert!(self.control_interval > 0, "Control interval must be greater than 0");
+        assert!(self.num_channels <= MAX_FRAME_CHANNELS, "GraphFlow supports at most {} channels", MAX_FRAME_CHANNELS);
+        assert!(
+            self.buffer_size > 0 && (self.buffer_size as usize).is_multiple_of(self.num_channels),
+            "Buffer size must be a positive multiple of the channel count",
+        );
+        let time = Time::new(self.sample_rate);
+        let plan = Arc::new(Plan::empty(time, self.num_channels));
 
         GraphFlow {
-            time: Time::new(self.sample_rate),
+            time,
             num_channels: self.num_channels,
+            control_interval: self.control_interval,
             blocks: HashMap::new(),
+            block_order: Vec::new(),
 
             buffer_size: self.buffer_size,
-            outputs: HashSet::new(),
+            max_buffer_size: self.buffer_size,
+            queue: OutputQueue::new(self.buffer_size as usize),
+            outputs: HashMap::new(),
+            graph_inputs: Vec::new(),
+            graph_input_edges: HashSet::new(),
+            taps: Taps::default(),
+            stats: Arc::new(GraphStats::default()),
 
             graph: DiGraph::new(),
             topo_sort: Vec::new(),
             block_map: HashMap::new(),
             node_map: HashMap::new(),
 
-            thread_pool: ThreadPool::new(20, result_tx.clone()),
-            result_rx,
+            thread_pool: ThreadPool::new(self.num_threads, Arc::clone(&plan)),
+            running: Arc::clone(&plan),
+            plan,
+            exchange: None,
+            history: History::default(),
         }
     }
 }
 
+/// 连接的种类
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeKind {
+    #[default]
+    Normal,
+    /// 反馈连接：读取上游在上一个缓冲区的输出，排序时不计入
+    Feedback,
+}
+
+/// 一条连接的两端
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EdgeInfo {
+    pub from: Port,
+    pub to: Port,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Edge {
+    from_port: usize,
+    to_port: usize,
+    kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    /// 图中没有该音频块
+    UnknownBlock(BlockId),
+    /// 端口超出音频块在该方向上声明的端口数
+    PortOutOfRange { port: Port, direction: PortDirection, port_len: usize },
+    /// 音频块在该方向上没有这个名字的端口
+    UnknownPort { block_id: BlockId, direction: PortDirection, name: String },
+    /// 音频块某个方向上的端口数超过 `MAX_PORT_LEN`
+    InvalidPortLen(usize),
+    /// 音频块没有这个名字的参数
+    UnknownParam { block_id: BlockId, name: String },
+    /// 同一方向上有重名的端口
+    DuplicatePortName(String),
+    /// 连接两端的信号种类不兼容（事件端口只能与事件端口相连）
+    KindMismatch { from: SignalKind, to: SignalKind },
+    /// 两个端口之间已经存在连接
+    DuplicateEdge { from: Port, to: Port },
+    /// 由普通连接构成的环，需要改用反馈连接
+    Cycle(BlockId),
+    /// 端口没有连到图流输出
+    NotAnOutput(Port),
+    /// 撤销或重做的命令无法执行，说明图流在编辑历史之外被修改过；图流与历史都保持原样
+    HistoryDiverged,
+}
+
+impl std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphError::UnknownBlock(block_id) => write!(f, "unknown block {:?}", block_id),
+            GraphError::PortOutOfRange { port, direction, port_len } => write!(
+                f, "{:?} port {} of block {:?} is out of range (port_len {})",
+                direction, port.port, port.block_id, port_len,
+            ),
+            GraphError::UnknownPort { block_id, direction, name } => {
+                write!(f, "block {:?} has no {:?} port named {:?}", block_id, direction, name)
+            }
+            GraphError::InvalidPortLen(port_len) => {
+                write!(f, "port_len {} is greater than {}", port_len, MAX_PORT_LEN)
+            }
+            GraphError::UnknownParam { block_id, name } => write!(f, "block {:?} has no param named {:?}", block_id, name),
+            GraphError::DuplicatePortName(name) => write!(f, "duplicate port name {:?}", name),
+            GraphError::KindMismatch { from, to } => write!(f, "cannot connect {:?} port to {:?} port", from, to),
+            GraphError::DuplicateEdge { from, to } => write!(
+                f, "block {:?} port {} is already connected to block {:?} port {}",
+                from.block_id, from.port, to.block_id, to.port,
+            ),
+            GraphError::Cycle(block_id) => write!(f, "cycle of normal edges through block {:?}", block_id),
+            GraphError::NotAnOutput(port) => {
+                write!(f, "block {:?} port {} is not connected to the output", port.block_id, port.port)
+            }
+            GraphError::HistoryDiverged => write!(f, "graph has diverged from its edit history"),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
 pub struct GraphFlow {
     time: Time,
     num_channels: usize,
+    control_interval: usize,
     blocks: HashMap<BlockId, Block>,
+    /// 按添加顺序排列的块，petgraph 删除节点时会打乱下标
+    block_order: Vec<BlockId>,
     buffer_size: u32,
-    outputs: HashSet<Port>,
-    graph: DiGraph<f32, ()>,
+    max_buffer_size: u32,  // 传给 Processor::prepare 的最大缓冲区
+    /// 最后一个处理块中还没交给宿主的输出
+    queue: OutputQueue,
+    /// 混入输出的端口及其独奏开关
+    outputs: HashMap<Port, SoloHandle>,
+    /// 作为子图运行时由外层写入的输入，及其连到的内部端口
+    graph_inputs: Vec<PortSpec>,
+    graph_input_edges: HashSet<(usize, Port)>,
+    /// 探针，读取端丢弃后在下一次添加探针时清理
+    taps: Taps,
+    stats: Arc<GraphStats>,
+    graph: DiGraph<f32, Edge>,
     topo_sort: Vec<petgraph::graph::NodeIndex>,
     block_map: HashMap<BlockId, petgraph::graph::NodeIndex>,
     node_map: HashMap<petgraph::graph::NodeIndex, BlockId>,
+    plan: Arc<Plan>,
     thread_pool: ThreadPool,
-    result_rx: mpsc::Receiver<ResultData>,
+    /// 拆分后与 `Engine` 交换运行计划，见 `GraphFlow::split`
+    exchange: Option<Arc<Exchange>>,
+    /// 拆分后引擎正在运行（或已经取到）的计划，新计划相对它淡化
+    running: Arc<Plan>,
+    pub(crate) history: History,
 }
 
 impl GraphFlow {
-    pub fn to_output(&mut self, from: Port) {
-        self.outputs.insert(from);
-    }
-
-    // pub fn add_listener(&mut self, to: Port) -> Listener {
-    //     let (sender, receiver) = mpsc::channel();
-    //     let id = BlockId::new();
-    //     let receiver = Arc::new(Mutex::new(receiver));
-
-    //     let data = Arc::new(Mutex::new(0.0));
-    //     let data_clone = Arc::clone(&data);
-    //     std::thread::spawn(move || {
-    //         loop {
-    //             *data.lock().unwrap() = receiver.lock().unwrap().recv().unwrap();
-    //         }
-    //     });
-
-    //     let block = move |_time: Time, _inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
-    //             // 接收数据并存入outputs
-    //             outputs[0].iter_mut().for_each(|output| *output = *data_clone.lock().unwrap());
-    //         };
-    //     let listener_block_id = self.add_block(
-    //         block.into_block()
-    //     );
-    //     self.connect(listener_block_id.port(0), to);
-
-    //     Listener { id, sender }
-    // }
-
-    pub fn add_block<M: BlockMarker>(&mut self, block: impl IntoBlock<M>) -> BlockId {
-        let id = BlockId::new();
-        let block = block.into_block();
+    pub fn sample_rate(&self) -> u32 {
+        self.time.sample_rate()
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    pub fn control_interval(&self) -> usize {
+        self.control_interval
+    }
+
+    /// 内部处理块的长度，每个块看到的缓冲区都是这个长度
+    pub fn buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+
+    /// 已经处理、还没交给宿主的帧数，即分块带来的额外延迟
+    pub fn queued_frames(&self) -> usize {
+        self.queue.queued() / self.num_channels
+    }
+
+    pub fn time(&self) -> Time {
+        self.time
+    }
+
+    pub fn to_output(&mut self, from: impl Into<PortRef>) -> Result<(), GraphError> {
+        let from = self.resolve(from.into(), PortDirection::Output)?;
+        // 只有音频端口能直接混入输出，控制端口需要先连到音频端口
+        let kind = self.spec(from, PortDirection::Output).kind;
+        if kind != SignalKind::Audio {
+            return Err(GraphError::KindMismatch { from: kind, to: SignalKind::Audio });
+        }
+        if !self.outputs.contains_key(&from) {
+            let solo = SoloHandle::default();
+            self.add_output(from, solo.clone());
+            self.history.record(Command::AddOutput(from, solo));
+        }
+        Ok(())
+    }
+
+    /// 把端口移出图流输出，返回它原来是否是输出
+    pub fn remove_output(&mut self, from: impl Into<PortRef>) -> bool {
+        let Ok(from) = self.resolve(from.into(), PortDirection::Output) else {
+            return false;
+        };
+        match self.take_output(from) {
+            Some(solo) => {
+                self.history.record(Command::RemoveOutput(from, solo));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn add_output(&mut self, port: Port, solo: SoloHandle) {
+        self.outputs.insert(port, solo);
+        self.compile();
+    }
+
+    pub(crate) fn take_output(&mut self, port: Port) -> Option<SoloHandle> {
+        let solo = self.outputs.remove(&port)?;
+        self.compile();
+        Some(solo)
+    }
+
+    pub fn add_block<M: BlockMarker>(&mut self, block: impl IntoBlock<M>) -> Result<BlockId, GraphError> {
+        let block_id = self.insert_block(BlockId::new(), block.into_block())?;
+        self.history.record(Command::AddBlock(block_id, None));
+        Ok(block_id)
+    }
+
+    /// 以给定的 id 添加音频块，加载工程时保留原来的 id
+    pub(crate) fn insert_block(&mut self, id: BlockId, mut block: Block) -> Result<BlockId, GraphError> {
+        for specs in [&block.ports.inputs, &block.ports.outputs] {
+            if specs.len() > MAX_PORT_LEN {
+                return Err(GraphError::InvalidPortLen(specs.len()));
+            }
+            let mut names = HashSet::new();
+            if let Some(spec) = specs.iter().find(|spec| !names.insert(spec.name.as_str())) {
+                return Err(GraphError::DuplicatePortName(spec.name.clone()));
+            }
+        }
+        // 新块还没有进入运行计划
+        let processor = unsafe { block.processor.get_mut() };
+        processor.set_control_interval(self.control_interval);
+        processor.prepare(self.sample_rate(), self.max_buffer_size as usize, self.num_channels);
+        block.latency = processor.latency();
+        self.attach_block(id, block);
+        Ok(id)
+    }
+
+    /// 把已经 `prepare` 过的块放回图中，撤销删除时使用
+    pub(crate) fn attach_block(&mut self, id: BlockId, block: Block) {
         // add to blocks
         self.blocks.insert(id, block);
+        self.block_order.push(id);
         // add to graph
         let node = self.graph.add_node(1.0);
         self.block_map.insert(id, node);
         self.node_map.insert(node, id);
 
-        // topo sort
-        self.sort();
+        // topo sort（孤立节点不会成环）
+        let _ = self.rebuild();
+    }
 
-        id
+    /// 删除音频块，同时断开其所有连接并移出输出，返回该块是否存在
+    ///
+    /// 块由编辑历史保管，撤销时连同连接与输出一起恢复。
+    pub fn remove_block(&mut self, block_id: BlockId) -> bool {
+        if !self.blocks.contains_key(&block_id) {
+            return false;
+        }
+        let edges = self.edges().into_iter()
+            .filter(|edge| edge.from.block_id == block_id || edge.to.block_id == block_id)
+            .collect();
+        let outputs = self.outputs.iter()
+            .filter(|(port, _)| port.block_id == block_id)
+            .map(|(port, solo)| (*port, solo.clone()))
+            .collect();
+        let graph_inputs = self.graph_input_edges.iter()
+            .filter(|(_, port)| port.block_id == block_id)
+            .copied()
+            .collect();
+        let block = self.detach_block(block_id).unwrap();
+        self.history.record(Command::RemoveBlock { block_id, block: Some(block), edges, outputs, graph_inputs });
+        true
     }
 
-    pub fn get_block(&self, block_id: &BlockId) -> &Block {
-        self.blocks.get(block_id).unwrap()
+    /// 把块移出图，不记录历史
+    pub(crate) fn detach_block(&mut self, block_id: BlockId) -> Option<Block> {
+        let mut block = self.blocks.remove(&block_id)?;
+        self.block_order.retain(|id| *id != block_id);
+        let node = self.block_map.remove(&block_id).unwrap();
+        self.node_map.remove(&node);
+
+        // 下游节点的入度与输入表
+        let post_nodes = self.graph.neighbors_directed(node, petgraph::Direction::Outgoing).collect::<Vec<_>>();
+        for post_node in post_nodes {
+            let Some(&post_block_id) = self.node_map.get(&post_node) else {
+                continue;  // 反馈自环
+            };
+            let post_block = self.block_mut(&post_block_id);
+            if let Some(port_match) = post_block.inputs.remove(&block_id) {
+                post_block.d_in -= port_match.len();
+            }
+            post_block.feedback_inputs.remove(&block_id);
+        }
+        self.outputs.retain(|port, _| port.block_id != block_id);
+        self.graph_input_edges.retain(|(_, port)| port.block_id != block_id);
+        self.taps.lock().unwrap().retain(|(port, _)| port.block_id != block_id);
+
+        // petgraph 会把最后一个节点换到被删除的位置
+        let last_node = petgraph::graph::NodeIndex::new(self.graph.node_count() - 1);
+        self.graph.remove_node(node);
+        if last_node != node {
+            let moved_block_id = self.node_map.remove(&last_node).unwrap();
+            self.node_map.insert(node, moved_block_id);
+            self.block_map.insert(moved_block_id, node);
+        }
+
+        // 块自己的输入表随入边一起清空，放回时重新连接
+        block.inputs.clear();
+        block.feedback_inputs.clear();
+        block.d_in = 0;
+
+        // topo sort（删边不会成环）
+        let _ = self.rebuild();
+
+        Some(block)
+    }
+
+    pub fn get_block(&self, block_id: &BlockId) -> Result<&Block, GraphError> {
+        self.blocks.get(block_id).ok_or(GraphError::UnknownBlock(*block_id))
+    }
+
+    pub fn get_block_mut(&mut self, block_id: &BlockId) -> Result<&mut Block, GraphError> {
+        self.blocks.get_mut(block_id).ok_or(GraphError::UnknownBlock(*block_id))
+    }
+
+    /// 音频块声明的端口，供编辑器展示
+    pub fn ports(&self, block_id: &BlockId) -> Result<&PortLayout, GraphError> {
+        Ok(&self.get_block(block_id)?.ports)
+    }
+
+    pub fn params(&self, block_id: &BlockId) -> Result<&[ParamHandle], GraphError> {
+        Ok(&self.get_block(block_id)?.params)
+    }
+
+    pub(crate) fn all_params(&self) -> Vec<ParamHandle> {
+        self.blocks.values().flat_map(|block| block.params.iter().cloned()).collect()
+    }
+
+    /// 参数句柄，可以交给 UI 或网络线程
+    pub fn param(&self, block_id: &BlockId, name: &str) -> Result<ParamHandle, GraphError> {
+        self.params(block_id)?.iter()
+            .find(|param| param.name() == name)
+            .cloned()
+            .ok_or_else(|| GraphError::UnknownParam { block_id: *block_id, name: name.to_string() })
+    }
+
+    /// 设置参数并记入编辑历史；直接通过句柄设置的值不能撤销
+    pub fn set_param(&mut self, block_id: &BlockId, name: &str, value: f32) -> Result<(), GraphError> {
+        let param = self.param(block_id, name)?;
+        let from = param.get();
+        param.set(value);
+        self.history.record(Command::SetParam { block_id: *block_id, name: name.to_string(), from, to: param.get() });
+        Ok(())
+    }
+
+    /// 按添加顺序排列的音频块，撤销删除后恢复的块排在最后
+    pub fn block_ids(&self) -> Vec<BlockId> {
+        self.block_order.clone()
+    }
+
+    /// 所有连接，按两端的块在 `block_ids` 中的顺序与端口排列
+    pub fn edges(&self) -> Vec<EdgeInfo> {
+        let position = self.block_order.iter().enumerate().map(|(i, block_id)| (*block_id, i)).collect::<HashMap<_, _>>();
+        let mut edges = self.graph.edge_references().map(|edge| {
+            let weight = edge.weight();
+            EdgeInfo {
+                from: self.node_map[&edge.source()].port(weight.from_port),
+                to: self.node_map[&edge.target()].port(weight.to_port),
+                kind: weight.kind,
+            }
+        }).collect::<Vec<_>>();
+        edges.sort_by_key(|edge| (position[&edge.from.block_id], edge.from.port, position[&edge.to.block_id], edge.to.port));
+        edges
+    }
+
+    /// 混入输出的端口
+    pub fn outputs(&self) -> HashSet<Port> {
+        self.outputs.keys().copied().collect()
+    }
+
+    /// 音频块的模式开关，可以在播放中切换
+    pub fn mode(&self, block_id: &BlockId) -> Result<ModeHandle, GraphError> {
+        Ok(self.get_block(block_id)?.mode.clone())
+    }
+
+    /// 切换模式并记入编辑历史；直接通过句柄切换的模式不能撤销
+    pub fn set_mode(&mut self, block_id: &BlockId, mode: BlockMode) -> Result<(), GraphError> {
+        let handle = self.mode(block_id)?;
+        let from = handle.get();
+        handle.set(mode);
+        self.history.record(Command::SetMode { block_id: *block_id, from, to: mode });
+        Ok(())
+    }
+
+    /// 输出端口的独奏开关，端口必须已经通过 `to_output` 连到输出
+    pub fn solo(&self, port: impl Into<PortRef>) -> Result<SoloHandle, GraphError> {
+        let port = self.resolve(port.into(), PortDirection::Output)?;
+        self.outputs.get(&port).cloned().ok_or(GraphError::NotAnOutput(port))
+    }
+
+    /// 切换独奏并记入编辑历史；直接通过句柄切换的独奏不能撤销
+    pub fn set_solo(&mut self, port: impl Into<PortRef>, solo: bool) -> Result<(), GraphError> {
+        let port = self.resolve(port.into(), PortDirection::Output)?;
+        let handle = self.solo(port)?;
+        let from = handle.get();
+        handle.set(solo);
+        self.history.record(Command::SetSolo { port, from, to: solo });
+        Ok(())
     }
 
-    pub fn get_block_mut(&mut self, block_id: &BlockId) -> &mut Block {
+    /// 运行计划使用的拓扑序，反馈连接不参与排序
+    pub fn topo_order(&self) -> Vec<BlockId> {
+        self.topo_sort.iter().map(|node| self.node_map[node]).collect()
+    }
+
+    // 内部使用，调用前 block_id 已经校验过
+    fn block_mut(&mut self, block_id: &BlockId) -> &mut Block {
         self.blocks.get_mut(block_id).unwrap()
     }
 
-    pub fn connect(&mut self, from: Port, to: Port) {
+    /// 把名字解析为下标，并检查下标是否越界
+    pub(crate) fn resolve(&self, port: PortRef, direction: PortDirection) -> Result<Port, GraphError> {
+        let ports = self.ports(&port.block_id())?;
+        let port = match port {
+            PortRef::Index(port) => port,
+            PortRef::Named(block_id, name) => match ports.find(direction, &name) {
+                Some(index) => block_id.port(index),
+                None => return Err(GraphError::UnknownPort { block_id, direction, name }),
+            },
+        };
+        let port_len = ports.specs(direction).len();
+        if port.port >= port_len {
+            return Err(GraphError::PortOutOfRange { port, direction, port_len });
+        }
+        Ok(port)
+    }
+
+    pub(crate) fn spec(&self, port: Port, direction: PortDirection) -> &PortSpec {
+        &self.blocks[&port.block_id].ports.specs(direction)[port.port]
+    }
+
+    /// 普通连接，若与已有的普通连接成环则撤销并返回错误
+    pub fn connect(&mut self, from: impl Into<PortRef>, to: impl Into<PortRef>) -> Result<(), GraphError> {
+        self.connect_with(from.into(), to.into(), EdgeKind::Normal)
+    }
+
+    /// 反馈连接，`to` 读到的是 `from` 在上一个缓冲区的输出，因此可以成环
+    pub fn connect_feedback(&mut self, from: impl Into<PortRef>, to: impl Into<PortRef>) -> Result<(), GraphError> {
+        self.connect_with(from.into(), to.into(), EdgeKind::Feedback)
+    }
+
+    fn connect_with(&mut self, from: PortRef, to: PortRef, kind: EdgeKind) -> Result<(), GraphError> {
+        let from = self.resolve(from, PortDirection::Output)?;
+        let to = self.resolve(to, PortDirection::Input)?;
+        self.add_edge(EdgeInfo { from, to, kind })?;
+        self.history.record(Command::Connect(EdgeInfo { from, to, kind }));
+        Ok(())
+    }
+
+    /// 添加连接，不记录历史
+    pub(crate) fn add_edge(&mut self, edge: EdgeInfo) -> Result<(), GraphError> {
+        let EdgeInfo { from, to, kind } = edge;
+        // 撤销与重做时两端的块可能已经不在图中
+        if let Some(block_id) = [from.block_id, to.block_id].into_iter().find(|id| !self.blocks.contains_key(id)) {
+            return Err(GraphError::UnknownBlock(block_id));
+        }
+        let (from_kind, to_kind) = (self.spec(from, PortDirection::Output).kind, self.spec(to, PortDirection::Input).kind);
+        // 音频与控制之间由运行计划自动转换速率，事件只能连到事件
+        if (from_kind == SignalKind::Event) != (to_kind == SignalKind::Event) {
+            return Err(GraphError::KindMismatch { from: from_kind, to: to_kind });
+        }
+
         // 更新图结构
         let from_node = self.block_map[&from.block_id];
         let to_node = self.block_map[&to.block_id];
-        self.graph.add_edge(from_node, to_node, ());
 
         // 修改 Block
-        let to_block = self.get_block_mut(&to.block_id);
-        to_block.inputs.entry(from.block_id).and_modify(|port_match| {
-            port_match.insert((from.port, to.port));
-        }).or_insert({
-            let mut set = HashSet::new();
-            set.insert((from.port, to.port));
-            set
-        });
-        to_block.d_in += 1;
+        let to_block = self.block_mut(&to.block_id);
+        let port_pair = (from.port, to.port);
+        let connected = |inputs: &HashMap<BlockId, HashSet<(usize, usize)>>| {
+            inputs.get(&from.block_id).is_some_and(|port_match| port_match.contains(&port_pair))
+        };
+        if connected(&to_block.inputs) || connected(&to_block.feedback_inputs) {
+            return Err(GraphError::DuplicateEdge { from, to });
+        }
+        match kind {
+            EdgeKind::Normal => {
+                to_block.inputs.entry(from.block_id).or_default().insert(port_pair);
+                to_block.d_in += 1;
+            }
+            EdgeKind::Feedback => {
+                to_block.feedback_inputs.entry(from.block_id).or_default().insert(port_pair);
+            }
+        }
+
+        self.graph.add_edge(from_node, to_node, Edge { from_port: from.port, to_port: to.port, kind });
 
         // topo sort
-        self.sort();
+        if let Err(err) = self.rebuild() {
+            self.remove_edge(from, to);
+            return Err(err);
+        }
+        Ok(())
     }
 
-    fn sort(&mut self) { 
-        if let Ok(sorted) = toposort(&self.graph, None) {
-            self.topo_sort = sorted;
+    /// 断开两个端口之间的连接（普通或反馈），返回该连接是否存在
+    pub fn disconnect(&mut self, from: impl Into<PortRef>, to: impl Into<PortRef>) -> bool {
+        let (Ok(from), Ok(to)) = (self.resolve(from.into(), PortDirection::Output), self.resolve(to.into(), PortDirection::Input)) else {
+            return false;
+        };
+        match self.remove_edge(from, to) {
+            Some(kind) => {
+                self.history.record(Command::Disconnect(EdgeInfo { from, to, kind }));
+                true
+            }
+            None => false,
         }
     }
 
-    fn update_block_inputs(&mut self, block_id: &BlockId, node_id: petgraph::graph::NodeIndex) {
-        let mut inputs = IOData::new(128, self.buffer_size as usize);
-        let block = self.get_block(block_id);
+    /// 断开连接，不记录历史，返回被断开连接的种类
+    pub(crate) fn remove_edge(&mut self, from: Port, to: Port) -> Option<EdgeKind> {
+        let (Some(&from_node), Some(&to_node)) = (self.block_map.get(&from.block_id), self.block_map.get(&to.block_id)) else {
+            return None;
+        };
+        let (edge, kind) = self.graph.edges_connecting(from_node, to_node)
+            .find(|edge| (edge.weight().from_port, edge.weight().to_port) == (from.port, to.port))
+            .map(|edge| (edge.id(), edge.weight().kind))?;
+        self.graph.remove_edge(edge);
 
-        for pre_node in self.graph.neighbors_directed(node_id, petgraph::Direction::Incoming) {
-            let pre_block_id = self.node_map.get(&pre_node).unwrap();
-            let pre_block = self.get_block(pre_block_id);
-            let pre_ports = block.inputs.get(pre_block_id).unwrap();
-            for (from_port, to_port) in pre_ports {
-                inputs[*to_port].iter_mut().zip(pre_block.data[*from_port].iter()).for_each(|(input, from_data)| *input += *from_data);
+        // 修改 Block
+        let to_block = self.block_mut(&to.block_id);
+        let inputs = match kind {
+            EdgeKind::Normal => {
+                to_block.d_in -= 1;
+                &mut to_block.inputs
+            }
+            EdgeKind::Feedback => &mut to_block.feedback_inputs,
+        };
+        if let Some(port_match) = inputs.get_mut(&from.block_id) {
+            port_match.remove(&(from.port, to.port));
+            if port_match.is_empty() {
+                inputs.remove(&from.block_id);
             }
         }
 
-        let block = self.get_block_mut(block_id);
-        block.data = inputs;
-    }
+        // topo sort（删边不会成环）
+        let _ = self.rebuild();
 
-    fn process_node(&mut self, node_id: petgraph::graph::NodeIndex) {
-        let block_id = *self.node_map.get(&node_id).unwrap();
-        self.update_block_inputs(&block_id, node_id);
+        Some(kind)
+    }
 
-        let block_data = self.get_block(&block_id).data.clone();
-        let block_process = Arc::clone(&self.get_block(&block_id).process);
-        let time = self.time.clone();
-        let mut outputs = IOData::new(128, self.buffer_size as usize);
-        let num_channels = self.num_channels;
+    /// 只对普通连接排序，反馈连接在这里被断开
+    fn sort(&mut self) -> Result<(), GraphError> {
+        let normal_edges = EdgeFiltered::from_fn(&self.graph, |edge| edge.weight().kind == EdgeKind::Normal);
+        let sorted = toposort(&normal_edges, None)
+            .map_err(|cycle| GraphError::Cycle(self.node_map[&cycle.node_id()]))?;
+        self.topo_sort = sorted;
+        Ok(())
+    }
 
-        self.thread_pool.execute(move || {
-            (block_process)(time, &block_data, &mut outputs, num_channels);
-            (block_id, outputs)
+    /// 按新的拓扑序编译运行计划，所有缓冲区在这里分配
+    ///
+    /// 拆分后引擎可能正在运行旧计划，新计划只参照它的结构，编译好后交给引擎换上。
+    fn compile(&mut self) {
+        let topo = self.topo_order();
+        let Some(exchange) = self.exchange.clone() else {
+            self.plan = Arc::new(self.compile_plan(&topo, Base::Idle(&self.plan)));
+            self.thread_pool.set_plan(Arc::clone(&self.plan));
+            return;
+        };
+        exchange.publish(|taken| {
+            if taken {
+                self.running = Arc::clone(&self.plan);
+            }
+            self.plan = Arc::new(self.compile_plan(&topo, Base::Running(&self.running, self.time)));
+            Arc::clone(&self.plan)
         });
     }
 
-    fn reset_block(&mut self) {
-        for (_, block) in self.blocks.iter_mut() {
-            block.d_in_cur = block.d_in;
+    fn format(&self) -> Format {
+        Format {
+            max_buffer_size: self.max_buffer_size as usize,
+            num_channels: self.num_channels,
+            control_interval: self.control_interval,
         }
     }
 
-    fn process(&mut self) {
-        let topo_len = self.topo_sort.len();
+    fn compile_plan(&self, topo: &[BlockId], base: Base) -> Plan {
+        Plan::compile(topo, &self.blocks, &self.outputs, &self.graph_inputs, &self.graph_input_edges, self.format(), base)
+    }
 
-        let mut i = 0;
-        while i < topo_len {
-            // 持续判断下一个队首是否可以运行
-            while i < topo_len {
-                let next_node_id = self.topo_sort[i];
-                let next_block_id = self.node_map.get(&next_node_id).unwrap();
-                let next_block = self.get_block(next_block_id);
-                if next_block.d_in_cur == 0 {
-                    self.process_node(next_node_id);
-                    i += 1;
-                } else {
-                    break;
-                }
-            }
+    fn rebuild(&mut self) -> Result<(), GraphError> {
+        self.sort()?;
+        self.compile();
+        Ok(())
+    }
 
-            // 等待某个节点完成
-            if let Ok(ResultData {block_id, result_data}) = self.result_rx.recv() {
-                // 更新节点的数据
-                let block = self.get_block_mut(&block_id);
-                block.data = result_data;
-                let node_id = self.block_map.get(&block_id).unwrap();
-                let post_nodes = self.graph.neighbors_directed(*node_id, petgraph::Direction::Outgoing).collect::<Vec<_>>();
-                for post_node in post_nodes.iter() {
-                    let post_block_id = *self.node_map.get(&post_node).unwrap();
-                    let post_block = self.get_block_mut(&post_block_id);
-                    post_block.d_in_cur -= 1;
-                }
-            }
+    /// 回到时间零点，清空所有块的输出并调用 `Processor::reset`
+    ///
+    /// 拆分后由引擎在下一个缓冲区开始前完成。
+    pub fn reset(&mut self) {
+        if let Some(exchange) = &self.exchange {
+            exchange.request_reset();
+            return;
+        }
+        self.time = Time::new(self.sample_rate());
+        for block in self.blocks.values() {
+            // 持有 &mut self 时没有正在进行的运行
+            unsafe { block.processor.get_mut() }.reset();
+        }
+        self.plan.clear();
+        self.queue.clear();
+    }
+
+    fn prepare(&mut self) {
+        let sample_rate = self.sample_rate();
+        for block in self.blocks.values_mut() {
+            let processor = unsafe { block.processor.get_mut() };
+            processor.set_control_interval(self.control_interval);
+            processor.prepare(sample_rate, self.max_buffer_size as usize, self.num_channels);
+            block.latency = processor.latency();
         }
     }
 
+    /// 填满宿主的 `output[..buffer_size]`，长度任意，每次回调可以不同
+    ///
+    /// 图流总是按 `GraphFlowBuilder::buffer_size` 的固定长度处理：完整的块直接写入 `output`，
+    /// 末尾不足一块时多处理一块，剩下的部分留到下一次回调。
     pub fn run(&mut self, buffer_size: u32, output: &mut [f32]) {
-        self.buffer_size = buffer_size;
+        assert!(self.exchange.is_none(), "GraphFlow has been split, run the Engine instead");
+        let len = output.len().min(buffer_size as usize);
+        let mut queue = std::mem::take(&mut self.queue);
+        queue.fill(&mut output[..len], |block| self.run_block(block));
+        self.queue = queue;
+    }
+
+    /// 把运行交给返回的 `Engine`，之后在音频线程上调用 `Engine::run`
+    ///
+    /// 图流留在编辑线程上，添加、删除、连接照常进行：每次结构变化后新计划在这里编译，
+    /// 由引擎在下一个缓冲区换上并交叉淡化（见 `engine` 模块）。拆分后 `run` 不再可用，`time` 停在拆分时。
+    pub fn split(&mut self) -> Engine {
+        assert!(self.exchange.is_none(), "GraphFlow has already been split");
+        let exchange = Exchange::new();
+        self.exchange = Some(Arc::clone(&exchange));
+        let thread_pool = std::mem::replace(&mut self.thread_pool, ThreadPool::new(0, Arc::clone(&self.plan)));
+        self.running = Arc::clone(&self.plan);
+        Engine::new(
+            Arc::clone(&self.plan),
+            thread_pool,
+            exchange,
+            self.time,
+            self.buffer_size,
+            self.num_channels,
+            std::mem::take(&mut self.queue),
+            Arc::clone(&self.stats),
+            Arc::clone(&self.taps),
+        )
+    }
 
-        self.reset_block();
-        self.process();
+    pub fn is_split(&self) -> bool {
+        self.exchange.is_some()
+    }
+
+    /// 处理一个固定长度的块，把输出写入 `output`
+    fn run_block(&mut self, output: &mut [f32]) {
+        self.plan.process(self.time, self.buffer_size as usize, &self.thread_pool, &self.stats, &self.taps);
 
         // 收集输出
         output.fill(0.0);
-        for Port {block_id, port} in self.outputs.iter() {
-            let block = self.get_block(block_id);
-            output.iter_mut().zip(block.data[*port].iter()).for_each(|(sample, block_sample)| *sample += *block_sample);
+        self.plan.mix_outputs(output);
+        self.time.tick_by_buffer(self.buffer_size as u64, self.num_channels);
+    }
+
+    /// 图流输出相对于源头的总延迟（帧），所有输出已按它对齐
+    pub fn latency(&self) -> usize {
+        self.plan.latency()
+    }
+
+    /// 音频块的输出相对于图流源头的延迟（帧），包括它自己的延迟
+    pub fn block_latency(&self, block_id: &BlockId) -> Result<usize, GraphError> {
+        self.plan.block_latency(block_id).ok_or(GraphError::UnknownBlock(*block_id))
+    }
+
+    /// 块的 `Processor::latency` 在 `prepare` 之外改变后调用，重新计算补偿延迟
+    ///
+    /// 拆分后块在引擎中运行，只能使用添加或 `prepare` 时记下的延迟。
+    pub fn refresh_latency(&mut self) {
+        if self.exchange.is_none() {
+            for block in self.blocks.values_mut() {
+                // 持有 &mut self 时没有正在进行的运行
+                block.latency = unsafe { block.processor.get() }.latency();
+            }
+        }
+        self.compile();
+    }
+
+    /// 每个块与整个图流的耗时统计，以及相对实时长度的负载
+    pub fn profile(&self) -> Profile {
+        Profile {
+            blocks: self.blocks.iter().map(|(block_id, block)| (*block_id, block.stats.timing())).collect(),
+            graph: self.stats.stats.timing(),
+            load: self.stats.load(),
+            peak_load: self.stats.peak_load(),
+            overruns: self.stats.overruns(),
+        }
+    }
+
+    pub fn reset_profile(&mut self) {
+        self.blocks.values().for_each(|block| block.stats.reset());
+        self.stats.reset();
+    }
+
+    /// 探测输出端口，每次运行后把该端口的采样写入返回的 `Tap`
+    pub fn tap(&mut self, port: impl Into<PortRef>) -> Result<Tap, GraphError> {
+        self.tap_with_capacity(port, DEFAULT_TAP_CAPACITY)
+    }
+
+    pub fn tap_with_capacity(&mut self, port: impl Into<PortRef>, capacity: usize) -> Result<Tap, GraphError> {
+        let port = self.resolve(port.into(), PortDirection::Output)?;
+        let kind = self.spec(port, PortDirection::Output).kind;
+        if kind == SignalKind::Event {
+            return Err(GraphError::KindMismatch { from: kind, to: SignalKind::Audio });
+        }
+        let ring = Ring::new(capacity);
+        let mut taps = self.taps.lock().unwrap();
+        taps.retain(|(_, ring)| Arc::strong_count(ring) > 1);
+        taps.push((port, Arc::clone(&ring)));
+        Ok(Tap::new(ring, port, kind))
+    }
+
+    /// 把图流输入 `name` 连到内部端口，不存在时按该端口的种类新建
+    pub(crate) fn connect_graph_input(&mut self, name: &str, to: PortRef) -> Result<usize, GraphError> {
+        let to = self.resolve(to, PortDirection::Input)?;
+        let PortSpec { kind, layout, .. } = *self.spec(to, PortDirection::Input);
+        let input = match self.graph_inputs.iter().position(|spec| spec.name == name) {
+            Some(input) if self.graph_inputs[input].kind != kind => {
+                return Err(GraphError::KindMismatch { from: self.graph_inputs[input].kind, to: kind });
+            }
+            Some(input) => input,
+            None => {
+                self.graph_inputs.push(PortSpec { layout, ..PortSpec::new(name, kind) });
+                self.graph_inputs.len() - 1
+            }
+        };
+        self.add_graph_input_edge(input, to);
+        Ok(input)
+    }
+
+    pub(crate) fn add_graph_input_edge(&mut self, input: usize, to: Port) {
+        if self.graph_input_edges.insert((input, to)) {
+            self.compile();
+        }
+    }
+
+    pub(crate) fn graph_inputs(&self) -> &[PortSpec] {
+        &self.graph_inputs
+    }
+
+    /// 停掉工作线程，之后只在调用 `run` 的线程上运行，作为子图时使用
+    pub(crate) fn stop_workers(&mut self) {
+        self.thread_pool = ThreadPool::new(0, Arc::clone(&self.plan));
+    }
+
+    /// 跟随外层图流的控制间隔，控制端口的长度随之变化，因此在这里重新编译
+    pub(crate) fn set_control_interval(&mut self, control_interval: usize) {
+        if control_interval != self.control_interval {
+            self.control_interval = control_interval;
+            self.compile();
+        }
+    }
+
+    /// 跟随外层图流的格式，只在添加到外层或外层格式变化时调用
+    pub(crate) fn set_format(&mut self, sample_rate: u32, max_buffer_size: u32, num_channels: usize) {
+        if sample_rate != self.sample_rate() {
+            self.time = Time::new(sample_rate);
+        }
+        self.max_buffer_size = max_buffer_size;
+        self.num_channels = num_channels;
+        self.prepare();
+        self.compile();
+    }
+
+    /// 把 `ports` 对齐到其中最大延迟的延迟线，与 `ports` 一一对应，作为子图时由子图保管
+    pub(crate) fn align_outputs(&self, ports: &[Port]) -> Vec<Option<DelayLine>> {
+        let latencies = ports.iter().map(|port| self.plan.block_latency(&port.block_id).unwrap_or(0)).collect::<Vec<_>>();
+        let latency = latencies.iter().copied().max().unwrap_or(0);
+        ports.iter().zip(latencies).map(|(port, port_latency)| {
+            let frames = latency - port_latency;
+            let spec = self.spec(*port, PortDirection::Output);
+            (frames > 0).then(|| DelayLine::new(frames, spec, self.max_buffer_size as usize, self.format()))
+        }).collect()
+    }
+
+    /// 作为子图运行：读入外层的输入，把 `exposed` 中的内部输出经过 `delays` 对齐后依次写到 `outputs`
+    pub(crate) fn run_nested(
+        &mut self,
+        time: Time,
+        inputs: &IOData,
+        outputs: &mut IOData,
+        exposed: &[Port],
+        delays: &mut [Option<DelayLine>],
+    ) {
+        debug_assert_eq!(outputs.control_interval(), self.control_interval, "Nested control interval differs from the outer one");
+        self.time = time;
+        // 外层已经分块，内部直接跟随外层的块长度，不超过 `set_format` 给出的最大长度
+        self.buffer_size = outputs.buffer_size() as u32;
+        debug_assert!(self.buffer_size <= self.max_buffer_size, "Nested buffer exceeds the prepared size");
+        self.plan.write_graph_inputs(inputs);
+        self.plan.process(self.time, self.buffer_size as usize, &self.thread_pool, &self.stats, &self.taps);
+        for (to_port, (port, delay)) in exposed.iter().zip(delays.iter_mut()).enumerate() {
+            self.plan.read_output(*port, delay, outputs, to_port);
         }
-        self.time.tick_by_buffer(buffer_size as u64, self.num_channels);
     }
 }
 
 impl std::fmt::Debug for GraphFlow {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "* Graph Flow\n{:?}", self.graph)
+        writeln!(f, "* Graph Flow")?;
+        let names = |specs: &[PortSpec]| specs.iter().map(|spec| spec.name.as_str()).collect::<Vec<_>>().join(", ");
+        for block_id in self.topo_order() {
+            let block = &self.blocks[&block_id];
+            let name = block.block_type.as_ref().map_or("block", |block_type| block_type.name.as_str());
+            writeln!(f, "{} {}: ({}) -> ({})", name, block_id, names(&block.ports.inputs), names(&block.ports.outputs))?;
+        }
+        let port = |port: Port, direction| format!("{}:{}", port.block_id, self.spec(port, direction).name);
+        for edge in self.edges() {
+            let arrow = match edge.kind {
+                EdgeKind::Normal => "->",
+                EdgeKind::Feedback => "~>",
+            };
+            writeln!(f, "{} {} {}", port(edge.from, PortDirection::Output), arrow, port(edge.to, PortDirection::Input))?;
+        }
+        for output in self.outputs.keys() {
+            writeln!(f, "{} -> output", port(*output, PortDirection::Output))?;
+        }
+        Ok(())
     }
 }
 
-struct ResultData {
-    pub block_id: BlockId,
-    pub result_data: IOData,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{constant, pass};
 
-type Job = Box<dyn FnOnce() -> (BlockId, IOData) + Send + 'static>;
+    #[test]
+    fn disconnect_and_remove_block() {
+        let mut gf = GraphFlowBuilder::default().build();
+        let a = gf.add_block(constant).unwrap();
+        let b = gf.add_block(constant).unwrap();
+        let filter = gf.add_block(pass).unwrap();
+        gf.connect(a.port(0), filter.port(0)).unwrap();
+        gf.connect(b.port(0), filter.port(0)).unwrap();
+        gf.to_output(filter.port(0)).unwrap();
 
-struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
-}
+        let mut output = vec![0.0; 512];
+        gf.run(512, &mut output);
+        assert!(output.iter().all(|&sample| sample == 2.0));
+
+        assert!(gf.disconnect(b.port(0), filter.port(0)));
+        assert!(!gf.disconnect(b.port(0), filter.port(0)));
+        gf.run(512, &mut output);
+        assert!(output.iter().all(|&sample| sample == 1.0));
+
+        // 删除首个节点会让 petgraph 移动最后一个节点的索引
+        assert!(gf.remove_block(a));
+        assert!(!gf.remove_block(a));
+        assert_eq!(gf.get_block(&filter).unwrap().d_in, 0);
+        gf.connect(b.port(0), filter.port(0)).unwrap();
+        // 块与连接仍按添加顺序排列
+        assert_eq!(gf.block_ids(), [b, filter]);
+        assert_eq!(gf.edges(), [EdgeInfo { from: b.port(0), to: filter.port(0), kind: EdgeKind::Normal }]);
+        gf.run(512, &mut output);
+        assert!(output.iter().all(|&sample| sample == 1.0));
+
+        gf.remove_block(filter);
+        gf.run(512, &mut output);
+        assert!(output.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn feedback_edges() {
+        let accumulate = |_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
+            outputs[0].iter_mut().zip(inputs[0].iter()).for_each(|(output, input)| *output = *input + 1.0);
+        };
+        let mut gf = GraphFlowBuilder::default().build();
+        let acc = gf.add_block(accumulate).unwrap();
+        let filter = gf.add_block(pass).unwrap();
+        gf.connect(acc.port(0), filter.port(0)).unwrap();
+        gf.to_output(filter.port(0)).unwrap();
+
+        // 普通连接成环被拒绝，图保持不变
+        assert!(matches!(gf.connect(filter.port(0), acc.port(0)), Err(GraphError::Cycle(_))));
+        assert_eq!(gf.connect(acc.port(0), acc.port(0)), Err(GraphError::Cycle(acc)));
+        assert_eq!(gf.get_block(&acc).unwrap().d_in, 0);
+
+        // 反馈连接每个缓冲区延迟一次
+        gf.connect_feedback(filter.port(0), acc.port(0)).unwrap();
+        assert_eq!(gf.block_ids(), [acc, filter]);
+        assert_eq!(gf.topo_order(), [acc, filter]);
+        assert_eq!(gf.edges(), [
+            EdgeInfo { from: acc.port(0), to: filter.port(0), kind: EdgeKind::Normal },
+            EdgeInfo { from: filter.port(0), to: acc.port(0), kind: EdgeKind::Feedback },
+        ]);
+        assert_eq!(gf.outputs(), HashSet::from([filter.port(0)]));
+        let mut output = vec![0.0; 512];
+        for expected in 1..=3 {
+            gf.run(512, &mut output);
+            assert!(output.iter().all(|&sample| sample == expected as f32));
+        }
+
+        assert!(gf.disconnect(filter.port(0), acc.port(0)));
+        gf.run(512, &mut output);
+        assert!(output.iter().all(|&sample| sample == 1.0));
+    }
+
+    #[test]
+    fn validated_construction() {
+        let mut gf = GraphFlowBuilder::default().build();
+        let a = gf.add_block(constant).unwrap();
+        let filter = gf.add_block(Block::with_ports(pass, 2)).unwrap();
+        let unknown = BlockId::new();
+        assert_eq!(Block::new(pass).input_len(), DEFAULT_PORT_LEN);
 
-impl ThreadPool {
-    fn new(size: usize, result_sender: mpsc::Sender<ResultData>) -> ThreadPool {
-        assert!(size > 0);
+        assert_eq!(
+            gf.add_block(Block::with_ports(pass, MAX_PORT_LEN + 1)),
+            Err(GraphError::InvalidPortLen(MAX_PORT_LEN + 1)),
+        );
+        assert_eq!(gf.connect(unknown.port(0), filter.port(0)), Err(GraphError::UnknownBlock(unknown)));
+        assert_eq!(gf.to_output(unknown.port(0)), Err(GraphError::UnknownBlock(unknown)));
+        assert_eq!(
+            gf.connect(a.port(0), filter.port(2)),
+            Err(GraphError::PortOutOfRange { port: filter.port(2), direction: PortDirection::Input, port_len: 2 }),
+        );
+        assert_eq!(
+            gf.to_output(filter.port(5)),
+            Err(GraphError::PortOutOfRange { port: filter.port(5), direction: PortDirection::Output, port_len: 2 }),
+        );
+        assert!(gf.get_block(&unknown).is_err());
+        assert_eq!(
+            gf.param(&filter, "gain").err(),
+            Some(GraphError::UnknownParam { block_id: filter, name: "gain".to_string() }),
+        );
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        gf.connect(a.port(0), filter.port(1)).unwrap();
+        assert_eq!(
+            gf.connect(a.port(0), filter.port(1)),
+            Err(GraphError::DuplicateEdge { from: a.port(0), to: filter.port(1) }),
+        );
+        assert_eq!(
+            gf.connect_feedback(a.port(0), filter.port(1)),
+            Err(GraphError::DuplicateEdge { from: a.port(0), to: filter.port(1) }),
+        );
+        assert_eq!(gf.get_block(&filter).unwrap().d_in, 1);
+    }
+
+    #[test]
+    fn named_ports() {
+        let mut gf = GraphFlowBuilder::default().build();
+        let a = gf.add_block(constant).unwrap();
+        let gain = |_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
+            outputs[0].iter_mut().zip(inputs[0].iter().zip(inputs[1].iter()))
+                .for_each(|(output, (input, gain))| *output = *input * *gain);
+        };
+        let layout = PortLayout::new()
+            .input("in", SignalKind::Audio)
+            .input("gain", SignalKind::Control)
+            .output("out", SignalKind::Audio);
+        let vca = gf.add_block(Block::with_ports(gain, layout.clone())).unwrap();
+        assert_eq!(gf.ports(&vca), Ok(&layout));
+
+        gf.connect(a.named("0"), vca.named("in")).unwrap();
+        gf.to_output((vca, "out")).unwrap();
+        let silent = |_time: Time, _inputs: &IOData, _outputs: &mut IOData, _num_channels: usize| {};
+        let notes = gf.add_block(Block::with_ports(silent, PortLayout::new().output("notes", SignalKind::Event))).unwrap();
+        assert_eq!(
+            gf.connect(notes.named("notes"), vca.named("gain")),
+            Err(GraphError::KindMismatch { from: SignalKind::Event, to: SignalKind::Control }),
+        );
+        assert_eq!(
+            gf.connect(a.port(1), vca.named("out")),
+            Err(GraphError::UnknownPort { block_id: vca, direction: PortDirection::Input, name: "out".to_string() }),
+        );
+        assert!(gf.disconnect(a.port(0), vca.named("in")));
+
+        let duplicate = PortLayout::new().output("out", SignalKind::Audio).output("out", SignalKind::Event);
+        assert_eq!(
+            gf.add_block(Block::with_ports(gain, duplicate)).err(),
+            Some(GraphError::DuplicatePortName("out".to_string())),
+        );
+    }
+
+    #[test]
+    fn control_rate_conversion() {
+        let builder = GraphFlowBuilder { buffer_size: 8, num_channels: 1, num_threads: 0, control_interval: 4, ..Default::default() };
 
-        let mut workers = Vec::with_capacity(size);
+        // 控制到音频：逐段线性插值
+        let mut gf = builder.build();
+        let mut next = 0.0;
+        let lfo = Block::from_processor(FnMutProcessor(move |outputs: &mut IOData| {
+            for value in outputs[0].iter_mut() {
+                *value = next;
+                next += 1.0;
+            }
+        }, PortLayout::new().output("out", SignalKind::Control)));
+        let lfo = gf.add_block(lfo).unwrap();
+        let filter = gf.add_block(pass).unwrap();
+        gf.connect(lfo.named("out"), filter.port(0)).unwrap();
+        gf.to_output(filter.port(0)).unwrap();
+        assert!(gf.to_output(lfo.named("out")).is_err());
 
-        for id in 0..size {
-            workers.push(Worker::new(
-                id,
-                Arc::clone(&receiver),
-                result_sender.clone()),
-            );
+        let mut output = vec![0.0; 8];
+        gf.run(8, &mut output);
+        assert_eq!(output, [0.0, 0.0, 0.0, 0.0, 0.25, 0.5, 0.75, 1.0]);
+        output.fill(0.0);
+        gf.run(8, &mut output);
+        assert_eq!(output, [1.25, 1.5, 1.75, 2.0, 2.25, 2.5, 2.75, 3.0]);
+
+        // 音频到控制：每段取平均
+        let mut gf = builder.build();
+        let ramp = gf.add_block(|_time: Time, outputs: &mut IOData, _num_channels: usize| {
+            outputs[0].iter_mut().enumerate().for_each(|(i, sample)| *sample = i as f32);
+        }).unwrap();
+        let hold = Block::with_ports(|_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
+            for (segment, value) in outputs[0].chunks_mut(inputs.control_interval()).zip(inputs[0].iter()) {
+                segment.fill(*value);
+            }
+        }, PortLayout::new().input("in", SignalKind::Control).output("out", SignalKind::Audio));
+        let hold = gf.add_block(hold).unwrap();
+        gf.connect(ramp.port(0), hold.named("in")).unwrap();
+        gf.to_output(hold.named("out")).unwrap();
+        output.fill(0.0);
+        gf.run(8, &mut output);
+        assert_eq!(output, [1.5, 1.5, 1.5, 1.5, 5.5, 5.5, 5.5, 5.5]);
+    }
+
+    /// 只写输出的可变闭包
+    struct FnMutProcessor<F>(F, PortLayout);
+
+    impl<F: FnMut(&mut IOData) + Send> Processor for FnMutProcessor<F> {
+        fn ports(&self) -> PortLayout {
+            self.1.clone()
         }
 
-        ThreadPool { workers, sender }
+        fn process(&mut self, _time: Time, _inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+            (self.0)(outputs)
+        }
     }
 
-    fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() -> (BlockId, IOData) + Send + 'static,
-    {
-        let job = Box::new(f);
-        self.sender.send(job).unwrap();
+    /// 把输入延迟固定帧数并报告延迟，相当于前瞻限幅器
+    struct Lookahead(std::collections::VecDeque<f32>);
+
+    impl Processor for Lookahead {
+        fn latency(&self) -> usize {
+            self.0.len()
+        }
+
+        fn process(&mut self, _time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+            for (output, input) in outputs[0].iter_mut().zip(inputs[0].iter()) {
+                self.0.push_back(*input);
+                *output = self.0.pop_front().unwrap();
+            }
+        }
     }
-}
 
-struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
-    result_sender: mpsc::Sender<ResultData>,
-}
+    #[test]
+    fn delay_compensation() {
+        let mut gf = GraphFlowBuilder { buffer_size: 4, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let impulse = gf.add_block(|time: Time, outputs: &mut IOData, _num_channels: usize| {
+            if time.sample() == 0 {
+                outputs[0][0] = 1.0;
+            }
+        }).unwrap();
+        let lookahead = gf.add_block(Lookahead(vec![0.0; 3].into())).unwrap();
+        let sum = gf.add_block(pass).unwrap();
+        gf.connect(impulse.port(0), lookahead.port(0)).unwrap();
+        gf.connect(lookahead.port(0), sum.port(0)).unwrap();
+        gf.connect(impulse.port(0), sum.port(0)).unwrap();
+        gf.to_output(sum.port(0)).unwrap();
+        gf.to_output(impulse.port(0)).unwrap();
+        assert_eq!((gf.block_latency(&sum), gf.latency()), (Ok(3), 3));
 
-impl Worker {
-    fn new(
-        id: usize,
-        receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
-        result_sender: mpsc::Sender<ResultData>,
-    ) -> Worker {
-        let sender_clone = result_sender.clone();
-        let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv();
-
-            match job {
-                Ok(job) => {
-                    // println!("Worker {} got a job; executing.", id);
-                    let (block_id, result_data) = job();
-                    sender_clone.send(ResultData {
-                        block_id,
-                        result_data,
-                    }).unwrap();
-                }
-                Err(_) => {
-                    // println!("Worker {} disconnected; shutting down.", id);
-                    break;
-                }
+        // 两条路径在 sum 处对齐，直接输出的 impulse 也对齐到总延迟
+        let mut output = vec![0.0; 8];
+        gf.run(4, &mut output[..4]);
+        gf.run(4, &mut output[4..]);
+        assert_eq!(output, [0.0, 0.0, 0.0, 3.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    struct Counter {
+        prepared: Option<(u32, usize, usize)>,
+        count: f32,
+    }
+
+    impl Processor for Counter {
+        fn prepare(&mut self, sample_rate: u32, max_buffer: usize, num_channels: usize) {
+            self.prepared = Some((sample_rate, max_buffer, num_channels));
+        }
+
+        fn reset(&mut self) {
+            self.count = 0.0;
+        }
+
+        fn process(&mut self, _time: Time, _inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+            assert!(self.prepared.is_some());
+            self.count += 1.0;
+            outputs[0].fill(self.count);
+        }
+    }
+
+    #[test]
+    fn host_buffer_slicing() {
+        let mut gf = GraphFlowBuilder { buffer_size: 4, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let ramp = gf.add_block(|time: Time, outputs: &mut IOData, _num_channels: usize| {
+            // 块总是看到固定的长度
+            assert_eq!(outputs[0].len(), 4);
+            for (frame, sample) in outputs[0].iter_mut().enumerate() {
+                *sample = (time.sample() as usize + frame) as f32;
             }
-        });
+        }).unwrap();
+        gf.to_output(ramp.port(0)).unwrap();
+
+        let mut output = Vec::new();
+        for len in [3, 5, 1, 0, 7, 8] {
+            let mut buffer = vec![0.0; len];
+            gf.run(len as u32, &mut buffer);
+            output.extend_from_slice(&buffer);
+        }
+        assert_eq!(output, (0..24).map(|sample| sample as f32).collect::<Vec<_>>());
+        assert_eq!(gf.queued_frames(), 0);
+        let mut buffer = vec![0.0; 2];
+        gf.run(2, &mut buffer);
+        assert_eq!((buffer, gf.queued_frames(), gf.time().sample()), (vec![24.0, 25.0], 2, 28));
+    }
+
+    #[test]
+    fn stateful_processor() {
+        let mut gf = GraphFlowBuilder::default().build();
+        let counter = gf.add_block(Counter { prepared: None, count: 0.0 }).unwrap();
+        let filter = gf.add_block(pass).unwrap();
+        gf.connect(counter.port(0), filter.port(0)).unwrap();
+        gf.to_output(filter.port(0)).unwrap();
+
+        let mut output = vec![0.0; 512];
+        for expected in 1..=3 {
+            gf.run(512, &mut output);
+            assert!(output.iter().all(|&sample| sample == expected as f32));
+        }
+
+        gf.reset();
+        assert_eq!(gf.time().sample(), 0);
+        gf.run(512, &mut output);
+        assert!(output.iter().all(|&sample| sample == 1.0));
+    }
+
+    #[test]
+    fn parallel_branches() {
+        let mut gf = GraphFlowBuilder::default().build();
+        let mix = gf.add_block(pass).unwrap();
+        for _ in 0..16 {
+            let source = gf.add_block(constant).unwrap();
+            let filter = gf.add_block(pass).unwrap();
+            gf.connect(source.port(0), filter.port(0)).unwrap();
+            gf.connect(filter.port(0), mix.port(0)).unwrap();
+        }
+        gf.to_output(mix.port(0)).unwrap();
+
+        // 宿主的缓冲区长度可以变化，图流仍按固定的块长度处理，不重新分配
+        for buffer_size in [512, 256, 512, 1024, 64, 1024] {
+            let mut output = vec![0.0; buffer_size];
+            for _ in 0..20 {
+                gf.run(buffer_size as u32, &mut output);
+                assert!(output.iter().all(|&sample| sample == 16.0));
+            }
+        }
+    }
+
+    #[test]
+    fn inline_mode_runs_in_topological_order() {
+        use std::sync::Mutex;
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let order = Arc::clone(&order);
+            move |_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
+                order.lock().unwrap().push(name);
+                outputs[0].iter_mut().zip(inputs[0].iter()).for_each(|(output, input)| *output = *input + 1.0);
+            }
+        };
+
+        let builder = GraphFlowBuilder { num_threads: 0, ..Default::default() };
+        let mut gf = builder.build();
+        let c = gf.add_block(record("c")).unwrap();
+        let b = gf.add_block(record("b")).unwrap();
+        let a = gf.add_block(record("a")).unwrap();
+        gf.connect(a.port(0), b.port(0)).unwrap();
+        gf.connect(b.port(0), c.port(0)).unwrap();
+        gf.to_output(c.port(0)).unwrap();
 
-        Worker {
-            id,
-            thread: Some(thread),
-            result_sender,
+        let mut output = vec![0.0; 512];
+        for _ in 0..3 {
+            gf.run(512, &mut output);
+            assert!(output.iter().all(|&sample| sample == 3.0));
         }
+        assert_eq!(*order.lock().unwrap(), ["a", "b", "c"].repeat(3));
     }
 }
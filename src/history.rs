@@ -0,0 +1,465 @@
+//! 编辑历史：撤销、重做与变更通知
+//!
+//! 图流的每个公开修改（添加、删除块，连接、断开，输出路由，`GraphFlow::set_param`、`set_mode`
+//! 与 `set_solo`）都记成一条可以逆转的命令，同一事务中的命令一起撤销。修改、撤销与重做实际带来
+//! 的变化都以 `Edit` 通知监听者，编辑器据此更新界面，不必自己维护一份图。
+
+use crate::block::{Block, BlockId, Port};
+use crate::graph_flow::{EdgeInfo, GraphError, GraphFlow};
+use crate::switch::{BlockMode, SoloHandle};
+
+/// 默认最多保留的撤销事务数
+pub const DEFAULT_UNDO_LIMIT: usize = 100;
+
+/// 图流的一次变化
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    AddBlock(BlockId),
+    RemoveBlock(BlockId),
+    Connect(EdgeInfo),
+    Disconnect(EdgeInfo),
+    AddOutput(Port),
+    RemoveOutput(Port),
+    SetParam { block_id: BlockId, name: String, value: f32 },
+    SetMode { block_id: BlockId, mode: BlockMode },
+    SetSolo { port: Port, solo: bool },
+}
+
+impl Edit {
+    fn inverse(self) -> Edit {
+        match self {
+            Edit::AddBlock(block_id) => Edit::RemoveBlock(block_id),
+            Edit::RemoveBlock(block_id) => Edit::AddBlock(block_id),
+            Edit::Connect(edge) => Edit::Disconnect(edge),
+            Edit::Disconnect(edge) => Edit::Connect(edge),
+            Edit::AddOutput(port) => Edit::RemoveOutput(port),
+            Edit::RemoveOutput(port) => Edit::AddOutput(port),
+            // 旧值只有命令知道，见 `Command::inverse_edits`
+            edit @ (Edit::SetParam { .. } | Edit::SetMode { .. } | Edit::SetSolo { .. }) => edit,
+        }
+    }
+}
+
+type Listener = Box<dyn FnMut(&Edit) + Send>;
+
+pub(crate) enum Command {
+    /// 撤销后块由历史保管
+    AddBlock(BlockId, Option<Block>),
+    /// 删除后块由历史保管，同时记下它的连接、输出与图流输入
+    RemoveBlock {
+        block_id: BlockId,
+        block: Option<Block>,
+        edges: Vec<EdgeInfo>,
+        outputs: Vec<(Port, SoloHandle)>,
+        graph_inputs: Vec<(usize, Port)>,
+    },
+    Connect(EdgeInfo),
+    Disconnect(EdgeInfo),
+    /// 独奏开关随输出一起恢复，UI 持有的句柄仍然有效
+    AddOutput(Port, SoloHandle),
+    RemoveOutput(Port, SoloHandle),
+    SetParam { block_id: BlockId, name: String, from: f32, to: f32 },
+    SetMode { block_id: BlockId, from: BlockMode, to: BlockMode },
+    SetSolo { port: Port, from: bool, to: bool },
+}
+
+impl Command {
+    /// 命令生效时的变化，删除块时先断开连接、移出输出
+    fn edits(&self) -> Vec<Edit> {
+        match self {
+            Command::AddBlock(block_id, _) => vec![Edit::AddBlock(*block_id)],
+            Command::RemoveBlock { block_id, edges, outputs, .. } => edges.iter()
+                .map(|edge| Edit::Disconnect(*edge))
+                .chain(outputs.iter().map(|(port, _)| Edit::RemoveOutput(*port)))
+                .chain([Edit::RemoveBlock(*block_id)])
+                .collect(),
+            Command::Connect(edge) => vec![Edit::Connect(*edge)],
+            Command::Disconnect(edge) => vec![Edit::Disconnect(*edge)],
+            Command::AddOutput(port, _) => vec![Edit::AddOutput(*port)],
+            Command::RemoveOutput(port, _) => vec![Edit::RemoveOutput(*port)],
+            Command::SetParam { block_id, name, to, .. } => {
+                vec![Edit::SetParam { block_id: *block_id, name: name.clone(), value: *to }]
+            }
+            Command::SetMode { block_id, to, .. } => vec![Edit::SetMode { block_id: *block_id, mode: *to }],
+            Command::SetSolo { port, to, .. } => vec![Edit::SetSolo { port: *port, solo: *to }],
+        }
+    }
+
+    /// 命令被撤销时的变化
+    fn inverse_edits(&self) -> Vec<Edit> {
+        match self {
+            Command::SetParam { block_id, name, from, .. } => {
+                vec![Edit::SetParam { block_id: *block_id, name: name.clone(), value: *from }]
+            }
+            Command::SetMode { block_id, from, .. } => vec![Edit::SetMode { block_id: *block_id, mode: *from }],
+            Command::SetSolo { port, from, .. } => vec![Edit::SetSolo { port: *port, solo: *from }],
+            _ => self.edits().into_iter().rev().map(Edit::inverse).collect(),
+        }
+    }
+
+    /// 撤销命令，失败时图流保持原样
+    fn undo(&mut self, gf: &mut GraphFlow) -> Result<(), GraphError> {
+        match self {
+            Command::AddBlock(block_id, block) => *block = Some(detach(gf, *block_id)?),
+            Command::RemoveBlock { block_id, block, edges, outputs, graph_inputs } => {
+                attach(gf, *block_id, block)?;
+                // 连接失败时连同已经恢复的连接一起移除
+                if edges.iter().any(|edge| gf.add_edge(*edge).is_err()) {
+                    *block = Some(detach(gf, *block_id)?);
+                    return Err(GraphError::HistoryDiverged);
+                }
+                for (port, solo) in outputs.iter() {
+                    gf.add_output(*port, solo.clone());
+                }
+                for (input, port) in graph_inputs.iter() {
+                    gf.add_graph_input_edge(*input, *port);
+                }
+            }
+            Command::Connect(edge) => remove_edge(gf, edge)?,
+            Command::Disconnect(edge) => gf.add_edge(*edge).map_err(|_| GraphError::HistoryDiverged)?,
+            Command::AddOutput(port, _) => {
+                gf.take_output(*port).ok_or(GraphError::HistoryDiverged)?;
+            }
+            Command::RemoveOutput(port, solo) => add_output(gf, *port, solo)?,
+            Command::SetParam { block_id, name, from, .. } => set_param(gf, block_id, name, *from)?,
+            Command::SetMode { block_id, from, .. } => set_mode(gf, block_id, *from)?,
+            Command::SetSolo { port, from, .. } => set_solo(gf, *port, *from)?,
+        }
+        Ok(())
+    }
+
+    /// 重做命令，失败时图流保持原样
+    fn redo(&mut self, gf: &mut GraphFlow) -> Result<(), GraphError> {
+        match self {
+            Command::AddBlock(block_id, block) => attach(gf, *block_id, block)?,
+            // 连接、输出与图流输入随块一起移除
+            Command::RemoveBlock { block_id, block, .. } => *block = Some(detach(gf, *block_id)?),
+            Command::Connect(edge) => gf.add_edge(*edge).map_err(|_| GraphError::HistoryDiverged)?,
+            Command::Disconnect(edge) => remove_edge(gf, edge)?,
+            Command::AddOutput(port, solo) => add_output(gf, *port, solo)?,
+            Command::RemoveOutput(port, _) => {
+                gf.take_output(*port).ok_or(GraphError::HistoryDiverged)?;
+            }
+            Command::SetParam { block_id, name, to, .. } => set_param(gf, block_id, name, *to)?,
+            Command::SetMode { block_id, to, .. } => set_mode(gf, block_id, *to)?,
+            Command::SetSolo { port, to, .. } => set_solo(gf, *port, *to)?,
+        }
+        Ok(())
+    }
+}
+
+fn detach(gf: &mut GraphFlow, block_id: BlockId) -> Result<Block, GraphError> {
+    gf.detach_block(block_id).ok_or(GraphError::HistoryDiverged)
+}
+
+/// 把历史保管的块放回图中，同一 id 的块已经存在时不放回
+fn attach(gf: &mut GraphFlow, block_id: BlockId, block: &mut Option<Block>) -> Result<(), GraphError> {
+    match block.take() {
+        Some(taken) if gf.get_block(&block_id).is_err() => {
+            gf.attach_block(block_id, taken);
+            Ok(())
+        }
+        taken => {
+            *block = taken;
+            Err(GraphError::HistoryDiverged)
+        }
+    }
+}
+
+fn remove_edge(gf: &mut GraphFlow, edge: &EdgeInfo) -> Result<(), GraphError> {
+    gf.remove_edge(edge.from, edge.to).map(|_| ()).ok_or(GraphError::HistoryDiverged)
+}
+
+fn add_output(gf: &mut GraphFlow, port: Port, solo: &SoloHandle) -> Result<(), GraphError> {
+    if gf.get_block(&port.block_id).is_err() {
+        return Err(GraphError::HistoryDiverged);
+    }
+    gf.add_output(port, solo.clone());
+    Ok(())
+}
+
+fn set_param(gf: &GraphFlow, block_id: &BlockId, name: &str, value: f32) -> Result<(), GraphError> {
+    gf.param(block_id, name).map(|param| param.set(value)).map_err(|_| GraphError::HistoryDiverged)
+}
+
+fn set_mode(gf: &GraphFlow, block_id: &BlockId, mode: BlockMode) -> Result<(), GraphError> {
+    gf.mode(block_id).map(|handle| handle.set(mode)).map_err(|_| GraphError::HistoryDiverged)
+}
+
+fn set_solo(gf: &GraphFlow, port: Port, solo: bool) -> Result<(), GraphError> {
+    gf.solo(port).map(|handle| handle.set(solo)).map_err(|_| GraphError::HistoryDiverged)
+}
+
+pub(crate) struct History {
+    /// 每项是一个事务，单独的修改自成一个事务；最早的事务超出 `limit` 后被丢弃
+    undo: Vec<Vec<Command>>,
+    redo: Vec<Vec<Command>>,
+    limit: usize,
+    /// 正在进行的事务及其嵌套深度
+    transaction: Vec<Command>,
+    depth: usize,
+    listeners: Vec<Listener>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            limit: DEFAULT_UNDO_LIMIT,
+            transaction: Vec::new(),
+            depth: 0,
+            listeners: Vec::new(),
+        }
+    }
+}
+
+impl History {
+    /// 记录已经生效的命令，新的修改使重做失效
+    pub(crate) fn record(&mut self, command: Command) {
+        self.notify(&command.edits());
+        self.redo.clear();
+        if self.depth == 0 {
+            self.push_undo(vec![command]);
+            return;
+        }
+        // 同一事务中连续设置同一个参数（拖动旋钮）只保留首尾两个值
+        if let (
+            Command::SetParam { block_id, name, to, .. },
+            Some(Command::SetParam { block_id: last_block_id, name: last_name, to: last_to, .. }),
+        ) = (&command, self.transaction.last_mut()) {
+            if block_id == last_block_id && name == last_name {
+                *last_to = *to;
+                return;
+            }
+        }
+        self.transaction.push(command);
+    }
+
+    fn push_undo(&mut self, commands: Vec<Command>) {
+        self.undo.push(commands);
+        if self.undo.len() > self.limit {
+            self.undo.drain(..self.undo.len() - self.limit);
+        }
+    }
+
+    fn notify(&mut self, edits: &[Edit]) {
+        for listener in self.listeners.iter_mut() {
+            for edit in edits {
+                listener(edit);
+            }
+        }
+    }
+
+    /// 结束所有嵌套的事务
+    fn commit(&mut self) {
+        self.depth = 0;
+        if !self.transaction.is_empty() {
+            let transaction = std::mem::take(&mut self.transaction);
+            self.push_undo(transaction);
+        }
+    }
+}
+
+impl GraphFlow {
+    /// 撤销最近一个事务，没有可撤销的修改时返回 `Ok(false)`；未结束的事务先被结束
+    ///
+    /// 事务中任一命令无法撤销时，已经撤销的命令被重做回去，图流与历史都保持原样。
+    pub fn undo(&mut self) -> Result<bool, GraphError> {
+        self.history.commit();
+        let Some(mut commands) = self.history.undo.pop() else {
+            return Ok(false);
+        };
+        let mut edits = Vec::new();
+        for i in (0..commands.len()).rev() {
+            if let Err(err) = commands[i].undo(self) {
+                for command in commands[i + 1..].iter_mut() {
+                    let _ = command.redo(self);
+                }
+                self.history.undo.push(commands);
+                return Err(err);
+            }
+            edits.extend(commands[i].inverse_edits());
+        }
+        self.history.notify(&edits);
+        self.history.redo.push(commands);
+        Ok(true)
+    }
+
+    pub fn redo(&mut self) -> Result<bool, GraphError> {
+        self.history.commit();
+        let Some(mut commands) = self.history.redo.pop() else {
+            return Ok(false);
+        };
+        let mut edits = Vec::new();
+        for i in 0..commands.len() {
+            if let Err(err) = commands[i].redo(self) {
+                for command in commands[..i].iter_mut().rev() {
+                    let _ = command.undo(self);
+                }
+                self.history.redo.push(commands);
+                return Err(err);
+            }
+            edits.extend(commands[i].edits());
+        }
+        self.history.notify(&edits);
+        self.history.push_undo(commands);
+        Ok(true)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.history.undo.is_empty() || !self.history.transaction.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.history.redo.is_empty()
+    }
+
+    /// 最多保留的撤销事务数，默认为 `DEFAULT_UNDO_LIMIT`；超出的最早的事务立即被丢弃
+    pub fn set_undo_limit(&mut self, limit: usize) {
+        self.history.limit = limit;
+        let excess = self.history.undo.len().saturating_sub(limit);
+        self.history.undo.drain(..excess);
+    }
+
+    /// 开始事务，到对应的 `end_transaction` 为止的修改一起撤销；事务可以嵌套
+    pub fn begin_transaction(&mut self) {
+        self.history.depth += 1;
+    }
+
+    pub fn end_transaction(&mut self) {
+        match self.history.depth {
+            0 => {}
+            1 => self.history.commit(),
+            _ => self.history.depth -= 1,
+        }
+    }
+
+    /// 丢弃所有撤销与重做记录，例如加载工程之后
+    pub fn clear_history(&mut self) {
+        let history = &mut self.history;
+        history.undo.clear();
+        history.redo.clear();
+        history.transaction.clear();
+        history.depth = 0;
+    }
+
+    /// 注册变更监听者，每次修改、撤销与重做带来的变化依次传给它
+    pub fn on_change(&mut self, listener: impl FnMut(&Edit) + Send + 'static) {
+        self.history.listeners.push(Box::new(listener));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::approx_eq;
+    use crate::channel::{Pan, PanLaw};
+    use crate::graph_flow::{EdgeKind, GraphFlowBuilder};
+    use crate::test_util::{constant, pass};
+
+    #[test]
+    fn undo_redo() {
+        let mut gf = GraphFlowBuilder { buffer_size: 8, num_channels: 2, num_threads: 0, ..Default::default() }.build();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let edits = Arc::clone(&log);
+        gf.on_change(move |edit| edits.lock().unwrap().push(edit.clone()));
+
+        let source = gf.add_block(constant).unwrap();
+        gf.begin_transaction();
+        let pan = gf.add_block(Pan::new(PanLaw::Linear)).unwrap();
+        gf.connect(source.port(0), pan.named("in")).unwrap();
+        gf.to_output(pan.named("out")).unwrap();
+        gf.end_transaction();
+        // 拖动旋钮：同一事务中的连续设置合并为一条
+        gf.begin_transaction();
+        gf.set_param(&pan, "pan", 0.5).unwrap();
+        gf.set_param(&pan, "pan", 1.0).unwrap();
+        gf.end_transaction();
+        assert!(gf.remove_block(pan));
+        assert_eq!(gf.block_ids(), vec![source]);
+
+        // 撤销删除：连接与输出一起恢复，块照常运行
+        let edge = EdgeInfo { from: source.port(0), to: pan.port(0), kind: EdgeKind::Normal };
+        assert_eq!(gf.undo(), Ok(true));
+        assert_eq!((gf.edges(), gf.outputs().len()), (vec![edge], 1));
+        gf.reset();
+        let mut output = vec![0.0; 8];
+        gf.run(8, &mut output);
+        assert!(approx_eq(output[6], 0.0) && approx_eq(output[7], 1.0));
+
+        assert_eq!(gf.undo(), Ok(true));
+        assert_eq!(gf.param(&pan, "pan").unwrap().get(), 0.0);
+        log.lock().unwrap().clear();
+        assert_eq!(gf.undo(), Ok(true));
+        assert_eq!((gf.block_ids(), gf.edges(), gf.outputs().len()), (vec![source], vec![], 0));
+        assert_eq!(*log.lock().unwrap(), vec![Edit::RemoveOutput(pan.port(0)), Edit::Disconnect(edge), Edit::RemoveBlock(pan)]);
+
+        assert_eq!((gf.redo(), gf.redo()), (Ok(true), Ok(true)));
+        assert_eq!((gf.edges(), gf.param(&pan, "pan").unwrap().get()), (vec![edge], 1.0));
+        // 新的修改使重做失效
+        assert!(gf.can_redo());
+        assert!(gf.disconnect(source.port(0), pan.named("in")));
+        assert!(!gf.can_redo());
+    }
+
+    #[test]
+    fn modes_solos_and_limit() {
+        let mut gf = GraphFlowBuilder { buffer_size: 8, num_threads: 0, ..Default::default() }.build();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let edits = Arc::clone(&log);
+        gf.on_change(move |edit| edits.lock().unwrap().push(edit.clone()));
+        let source = gf.add_block(constant).unwrap();
+        gf.to_output(source.port(0)).unwrap();
+
+        gf.set_mode(&source, BlockMode::Mute).unwrap();
+        gf.set_solo(source.port(0), true).unwrap();
+        log.lock().unwrap().clear();
+        assert_eq!((gf.undo(), gf.undo()), (Ok(true), Ok(true)));
+        assert_eq!((gf.mode(&source).unwrap().get(), gf.solo(source.port(0)).unwrap().get()), (BlockMode::Active, false));
+        assert_eq!(*log.lock().unwrap(), vec![
+            Edit::SetSolo { port: source.port(0), solo: false },
+            Edit::SetMode { block_id: source, mode: BlockMode::Active },
+        ]);
+
+        // 只保留最近的两个事务
+        gf.set_undo_limit(2);
+        assert_eq!((gf.undo(), gf.undo(), gf.undo()), (Ok(true), Ok(true), Ok(false)));
+        assert_eq!(gf.block_ids(), vec![]);
+        assert_eq!((gf.redo(), gf.redo(), gf.redo()), (Ok(true), Ok(true), Ok(true)));
+        gf.set_undo_limit(1);
+        assert_eq!((gf.undo(), gf.undo()), (Ok(true), Ok(false)));
+    }
+
+    #[test]
+    fn diverged_history() {
+        let mut gf = GraphFlowBuilder { buffer_size: 8, num_threads: 0, ..Default::default() }.build();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let edits = Arc::clone(&log);
+        gf.on_change(move |edit| edits.lock().unwrap().push(edit.clone()));
+        let source = gf.add_block(constant).unwrap();
+        let pass = gf.add_block(pass).unwrap();
+        gf.begin_transaction();
+        gf.connect(source.port(0), pass.port(0)).unwrap();
+        gf.to_output(pass.port(0)).unwrap();
+        gf.end_transaction();
+
+        // 在历史之外断开连接：移出输出之后撤销连接失败，输出被放回，历史不变
+        gf.remove_edge(source.port(0), pass.port(0));
+        log.lock().unwrap().clear();
+        assert_eq!(gf.undo(), Err(GraphError::HistoryDiverged));
+        assert_eq!((gf.outputs().len(), gf.can_undo(), gf.can_redo()), (1, true, false));
+        assert!(log.lock().unwrap().is_empty());
+        assert_eq!(gf.undo(), Err(GraphError::HistoryDiverged));
+
+        // 重做时块已经被移除，同样保持原样
+        gf.clear_history();
+        assert!(gf.remove_block(pass));
+        assert_eq!(gf.undo(), Ok(true));
+        gf.detach_block(pass);
+        assert_eq!(gf.redo(), Err(GraphError::HistoryDiverged));
+        assert_eq!((gf.block_ids(), gf.can_redo()), (vec![source], true));
+    }
+}
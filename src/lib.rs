@@ -4,6 +4,24 @@ pub mod key;
 pub mod musiblock;
 pub mod graph_flow;
 pub mod block;
+pub mod event;
+pub mod param;
+pub mod automation;
+pub mod subgraph;
+pub mod project;
+pub mod dot;
+pub mod tap;
+pub mod switch;
+pub mod profile;
+pub mod channel;
+pub mod oversample;
+pub mod engine;
+pub mod history;
+pub mod poly;
+pub mod render;
+mod schedule;
+#[cfg(test)]
+mod test_util;
 
 
 pub mod config {
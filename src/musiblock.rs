@@ -1,8 +1,11 @@
 use std::collections::HashMap;
 use std::f32::consts::PI;
 use log::debug;
+use serde::{Deserialize, Serialize};
 
 use crate::ClockTime;
+use crate::block::{IOData, PortLayout, Processor, SignalKind, Time};
+use crate::event::MidiMessage;
 
 use super::db_to_vol;
 const DOUBLE_PI: f32 = 2.0 * PI;
@@ -118,6 +121,28 @@ impl MidiSynth<AdditiveUnit> for AdditiveSynth {
             vol: db_to_vol(midi_msg[2] as f32 / 12.7),
         }
     }
+
+    fn respawn(&self, unit: &mut AdditiveUnit, midi_msg: &[u8]) {
+        if unit.oscs.len() != self.osc_num || unit.harmonic_vols.len() != self.harmonic_vols.len() {
+            *unit = self.spawn(midi_msg);
+            return;
+        }
+        let base_freq = midi_to_freq(midi_msg[1]);
+        for (i, osc) in unit.oscs.iter_mut().enumerate() {
+            *osc = Oscillator::new(base_freq * (i + 1) as f32, self.sample_rate);
+        }
+        unit.harmonic_vols.copy_from_slice(&self.harmonic_vols);
+        // 节点数不变时 `clone_from` 沿用原来的内存
+        let env = &mut unit.env_master;
+        env.nodes.clone_from(&self.env_master.nodes);
+        env.sample_rate = self.env_master.sample_rate;
+        env.current_time = self.env_master.current_time;
+        env.current_value = self.env_master.current_value;
+        env.current_index = self.env_master.current_index;
+        env.in_hold = self.env_master.in_hold;
+        env.ready_release = self.env_master.ready_release;
+        unit.vol = db_to_vol(midi_msg[2] as f32 / 12.7);
+    }
 }
 
 pub trait MidiUnit {
@@ -127,6 +152,11 @@ pub trait MidiUnit {
 
 pub trait MidiSynth<U: MidiUnit> {
     fn spawn(&self, midi_msg: &[u8]) -> U;
+
+    /// 在已有的单元上重新起音，`SynthBlock` 在音频线程上调用；默认重新 `spawn`，实时使用时应当覆盖以免分配
+    fn respawn(&self, unit: &mut U, midi_msg: &[u8]) {
+        *unit = self.spawn(midi_msg);
+    }
 }
 
 pub struct MidiRack<S, U>
@@ -185,9 +215,98 @@ where
     }
 }
 
+/// 图流中的合成器块：从事件端口 `midi` 读取音符，输出到 `out`
+///
+/// 与 `MidiRack` 相同的发声方式，但合成器归块所有，`max_voices` 个单元在创建时建好，
+/// 音频线程上只用 `MidiSynth::respawn` 重新起音，不加锁也不分配。单元用尽时忽略新的音符。
+pub struct SynthBlock<S, U>
+where
+    S: MidiSynth<U>,
+    U: MidiUnit,
+{
+    synth: S,
+    /// 每个单元及其正在演奏的音高，`None` 为空闲
+    voices: Vec<(Option<u8>, U)>,
+}
+
+impl<S, U> SynthBlock<S, U>
+where
+    S: MidiSynth<U>,
+    U: MidiUnit,
+{
+    pub fn new(synth: S, max_voices: usize) -> Self {
+        // 用力度为 0 的音符建好单元，之后只重新起音
+        let silent = [NOTE_ON_MSG, 69, 0];
+        let voices = (0..max_voices).map(|_| (None, synth.spawn(&silent))).collect();
+        SynthBlock { synth, voices }
+    }
 
+    fn send(&mut self, message: MidiMessage) {
+        let bytes = message.to_bytes();
+        let key = match message {
+            MidiMessage::NoteOn { key, .. } => {
+                // 同一个音高重新起音，否则找空闲的单元
+                let voice = self.voices.iter().position(|(playing, _)| *playing == Some(key))
+                    .or_else(|| self.voices.iter().position(|(playing, _)| playing.is_none()));
+                let Some(voice) = voice else {
+                    return;
+                };
+                let (playing, unit) = &mut self.voices[voice];
+                self.synth.respawn(unit, &bytes);
+                *playing = Some(key);
+                key
+            }
+            MidiMessage::NoteOff { key, .. } => key,
+            _ => return,
+        };
+        for (_, unit) in self.voices.iter_mut().filter(|(playing, _)| *playing == Some(key)) {
+            unit.send(&bytes);
+        }
+    }
 
-#[derive(Copy, Clone)]
+    fn tick(&mut self) -> f32 {
+        let mut out = 0.0;
+        for (playing, unit) in self.voices.iter_mut().filter(|(playing, _)| playing.is_some()) {
+            match unit.tick() {
+                Some(vol) => out += vol,
+                None => *playing = None,
+            }
+        }
+        out
+    }
+}
+
+impl<S, U> Processor for SynthBlock<S, U>
+where
+    S: MidiSynth<U> + Send,
+    U: MidiUnit + Send,
+{
+    fn reset(&mut self) {
+        self.voices.iter_mut().for_each(|(playing, _)| *playing = None);
+    }
+
+    fn ports(&self) -> PortLayout {
+        PortLayout::new()
+            .input("midi", SignalKind::Event)
+            .output("out", SignalKind::Audio)
+    }
+
+    fn process(&mut self, _time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+        let mut events = inputs.events(0).iter().peekable();
+        for frame in 0..outputs.num_frames() {
+            // 事件在它所在的帧之前生效
+            while let Some(event) = events.next_if(|event| event.frame <= frame) {
+                self.send(event.message);
+            }
+            let sample = self.tick();
+            outputs.channels_mut(0).for_each(|samples| samples[frame] = sample);
+        }
+    }
+}
+
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CurveType {
     Linear,
     // 下面的以后做，但是意味着 Envelop 的架构要整体改，目前只能邻近插值
@@ -196,7 +315,7 @@ pub enum CurveType {
     CubicSpline,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Node {
     pub t: f32,
     pub v: f32,
@@ -417,3 +536,35 @@ fn select_port<T: MidiIO>(midi_io: &T, descr: &str) -> Result<T::Port, Box<dyn E
         .ok_or("Invalid port number")?;
     Ok(port.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Sequencer;
+    use crate::graph_flow::GraphFlowBuilder;
+
+    #[test]
+    fn synth_block_reuses_voices() {
+        // 包络在 0.5 秒保持，松开后 1 秒结束
+        let mut gf = GraphFlowBuilder { sample_rate: 1000, buffer_size: 100, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let note_on = |key| MidiMessage::NoteOn { channel: 0, key, velocity: 100 };
+        let note_off = |key| MidiMessage::NoteOff { channel: 0, key, velocity: 0 };
+        let sequencer = gf.add_block(Sequencer::new(vec![
+            (0, note_on(60)), (0, note_on(64)), (0, note_on(67)), (100, note_off(60)), (100, note_off(64)), (100, note_off(67)),
+        ], 2000)).unwrap();
+        let synth = gf.add_block(SynthBlock::new(AdditiveSynth::new(4, 1000.0), 2)).unwrap();
+        gf.connect(sequencer.named("events"), synth.named("midi")).unwrap();
+        gf.to_output(synth.named("out")).unwrap();
+
+        // 只有两个单元，第三个音符被忽略；包络结束后单元空闲，下一轮循环重新起音
+        let mut buffer = vec![0.0; 100];
+        let mut peaks = Vec::new();
+        for _ in 0..30 {
+            gf.run(100, &mut buffer);
+            peaks.push(buffer.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs())));
+        }
+        assert!(peaks[..9].iter().all(|&peak| peak > 0.0));
+        assert!(peaks[11..20].iter().all(|&peak| peak == 0.0));
+        assert!(peaks[20] > 0.0);
+    }
+}
@@ -0,0 +1,422 @@
+//! 过采样：以图流采样率的整数倍运行一个块或子图
+//!
+//! `Oversample` 包装任意块，在边界上把音频输入插值到 `factor` 倍的采样率，处理后经抗混叠
+//! 滤波抽取回图流的采样率。内部块看到的 `Time` 与 `prepare` 都是提高后的采样率，因此失真、
+//! FM 等非线性块可以减少混叠；包装 `Subgraph` 时就得到以更高采样率运行的整个子图，
+//! 例如在 48 kHz 工程中运行 96 kHz 的合成器。
+//!
+//! 插值与抽取使用同一个 Kaiser 窗的线性相位 FIR 低通，每次滤波引入半个
+//! `OVERSAMPLE_TAPS_PER_PHASE` 的延迟，合计通过 `Processor::latency` 报告给图流补偿。
+//! 控制端口的值直接传递（内部的控制间隔同样乘以 `factor`），事件的帧偏移按倍数换算，
+//! 输入与输出的事件分别与插值、抽取后的音频一起延迟，事件驱动的合成器与音频输入保持对齐。
+//! 内部块参数的自动化同样按提高后的采样率求值。
+
+use std::f64::consts::PI;
+
+use crate::block::{Block, BlockMarker, IOData, IntoBlock, PortLayout, Processor, SignalKind, Time, DEFAULT_CONTROL_INTERVAL};
+use crate::event::{Event, EventBuffer, EVENT_CAPACITY};
+use crate::param::ParamHandle;
+
+/// 滤波器每个相位的抽头数，也是过采样引入的延迟（图流帧）
+pub const OVERSAMPLE_TAPS_PER_PHASE: usize = 32;
+
+/// 插值或抽取一次的延迟（图流帧）
+const HALF_DELAY: usize = OVERSAMPLE_TAPS_PER_PHASE / 2;
+
+const KAISER_BETA: f64 = 8.0;
+/// 通带截止频率，相对于图流的采样率
+const CUTOFF: f64 = 0.45;
+
+pub struct Oversample {
+    block: Block,
+    factor: usize,
+    filter: Vec<f32>,
+    num_channels: usize,
+    max_frames: usize,
+    /// 外层的控制间隔
+    control_interval: usize,
+    /// 内部块的输入输出，长度为外层的 `factor` 倍
+    inputs: IOData,
+    outputs: IOData,
+    /// 每个音频端口每个声道的滤波器历史，后面接着本次处理的数据
+    up: Vec<Vec<Vec<f32>>>,
+    down: Vec<Vec<Vec<f32>>>,
+    /// 每个事件输入端口还没送进内部块的事件，帧偏移为内部的帧
+    delayed: Vec<EventBuffer>,
+    /// 每个事件输出端口还没到时间的事件
+    pending: Vec<EventBuffer>,
+}
+
+impl Oversample {
+    pub fn new<M: BlockMarker>(block: impl IntoBlock<M>, factor: usize) -> Self {
+        assert!(factor >= 2, "Oversampling factor must be at least 2");
+        Oversample {
+            block: block.into_block(),
+            factor,
+            filter: lowpass(factor),
+            num_channels: 0,
+            max_frames: 0,
+            control_interval: DEFAULT_CONTROL_INTERVAL,
+            inputs: IOData::new(0, 0),
+            outputs: IOData::new(0, 0),
+            up: Vec::new(),
+            down: Vec::new(),
+            delayed: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    /// 按外层的控制间隔分配内部缓冲区，在 `prepare` 中调用
+    fn allocate(&mut self) {
+        let (factor, num_channels, control_interval) = (self.factor, self.num_channels, self.control_interval);
+        let capacity = self.max_frames * factor * num_channels;
+        let ports = &self.block.ports;
+        self.inputs = IOData::with_capacity(&ports.inputs, capacity, capacity, num_channels, control_interval * factor);
+        self.outputs = IOData::with_capacity(&ports.outputs, capacity, capacity, num_channels, control_interval * factor);
+
+        let history = |data: &IOData, len: usize| {
+            (0..data.port_len())
+                .map(|port| match data.kind(port) {
+                    SignalKind::Audio => vec![vec![0.0; len]; data.layout(port).num_channels()],
+                    _ => Vec::new(),
+                })
+                .collect::<Vec<_>>()
+        };
+        self.up = history(&self.inputs, OVERSAMPLE_TAPS_PER_PHASE + self.max_frames);
+        self.down = history(&self.outputs, self.filter.len() - 1 + self.max_frames * factor);
+        self.delayed = (0..self.inputs.port_len()).map(|_| EventBuffer::with_capacity(EVENT_CAPACITY)).collect();
+        self.pending = (0..self.outputs.port_len()).map(|_| EventBuffer::with_capacity(EVENT_CAPACITY)).collect();
+    }
+}
+
+impl Processor for Oversample {
+    fn prepare(&mut self, sample_rate: u32, max_buffer: usize, num_channels: usize) {
+        self.num_channels = num_channels;
+        self.max_frames = max_buffer / num_channels;
+        self.allocate();
+        // 持有 &mut self 时只有这里访问内部块
+        unsafe { self.block.processor.get_mut() }.prepare(sample_rate * self.factor as u32, max_buffer * self.factor, num_channels);
+    }
+
+    fn set_control_interval(&mut self, control_interval: usize) {
+        self.control_interval = control_interval;
+        unsafe { self.block.processor.get_mut() }.set_control_interval(control_interval * self.factor);
+    }
+
+    fn reset(&mut self) {
+        self.up.iter_mut().chain(self.down.iter_mut()).flatten().for_each(|history| history.fill(0.0));
+        self.delayed.iter_mut().chain(self.pending.iter_mut()).for_each(EventBuffer::clear);
+        unsafe { self.block.processor.get_mut() }.reset();
+    }
+
+    fn ports(&self) -> PortLayout {
+        self.block.ports.clone()
+    }
+
+    fn params(&self) -> Vec<ParamHandle> {
+        self.block.params.clone()
+    }
+
+    fn latency(&self) -> usize {
+        OVERSAMPLE_TAPS_PER_PHASE + unsafe { self.block.processor.get() }.latency().div_ceil(self.factor)
+    }
+
+    fn process(&mut self, time: Time, inputs: &IOData, outputs: &mut IOData, num_channels: usize) {
+        let factor = self.factor;
+        debug_assert_eq!(self.control_interval, inputs.control_interval(), "Oversample was prepared for another control interval");
+        let num_frames = outputs.num_frames();
+        let buffer_size = num_frames * factor * num_channels;
+        if self.inputs.buffer_size() != buffer_size {
+            self.inputs.set_buffer_size(buffer_size, num_channels);
+            self.outputs.set_buffer_size(buffer_size, num_channels);
+        }
+
+        for port in 0..inputs.port_len() {
+            match inputs.kind(port) {
+                SignalKind::Audio => {
+                    for (channel, history) in self.up[port].iter_mut().enumerate() {
+                        history[OVERSAMPLE_TAPS_PER_PHASE..][..num_frames].copy_from_slice(inputs.channel(port, channel));
+                        upsample(&self.filter, factor, history, self.inputs.channel_mut(port, channel));
+                        history.copy_within(num_frames..num_frames + OVERSAMPLE_TAPS_PER_PHASE, 0);
+                    }
+                }
+                SignalKind::Control => self.inputs[port].copy_from_slice(&inputs[port]),
+                SignalKind::Event => {
+                    // 与插值滤波器的延迟对齐
+                    let delayed = &mut self.delayed[port];
+                    for event in inputs.events(port) {
+                        delayed.push(Event { frame: (event.frame + HALF_DELAY) * factor, ..*event });
+                    }
+                    let events = self.inputs.events_mut(port);
+                    events.clear();
+                    delayed.drain_before(num_frames * factor, events);
+                }
+            }
+        }
+        for port in 0..self.outputs.port_len() {
+            self.outputs[port].fill(0.0);
+        }
+        self.outputs.clear_events();
+
+        let time = Time::at(time.sample_rate() * factor as u32, time.sample() * factor as u64);
+        // 外层已经按自己的采样率写入，内部块逐帧求值时要用提高后的采样率
+        for param in self.block.params.iter() {
+            param.automation().begin_buffer(time.sample(), time.sample_rate());
+        }
+        unsafe { self.block.processor.get_mut() }.process(time, &self.inputs, &mut self.outputs, num_channels);
+
+        let order = self.filter.len() - 1;
+        for port in 0..outputs.port_len() {
+            match outputs.kind(port) {
+                SignalKind::Audio => {
+                    for (channel, history) in self.down[port].iter_mut().enumerate() {
+                        history[order..][..num_frames * factor].copy_from_slice(self.outputs.channel(port, channel));
+                        downsample(&self.filter, factor, history, outputs.channel_mut(port, channel));
+                        history.copy_within(num_frames * factor..num_frames * factor + order, 0);
+                    }
+                }
+                SignalKind::Control => outputs[port].copy_from_slice(&self.outputs[port]),
+                SignalKind::Event => {
+                    let pending = &mut self.pending[port];
+                    for event in self.outputs.events(port) {
+                        pending.push(Event { frame: event.frame / factor + HALF_DELAY, ..*event });
+                    }
+                    let events = outputs.events_mut(port);
+                    events.clear();
+                    pending.drain_before(num_frames, events);
+                }
+            }
+        }
+    }
+}
+
+/// 插值：`history` 前 `OVERSAMPLE_TAPS_PER_PHASE` 个为上一次的输入，之后为本次的输入，`to` 为 `factor` 倍长
+fn upsample(filter: &[f32], factor: usize, history: &[f32], to: &mut [f32]) {
+    for (frame, samples) in to.chunks_exact_mut(factor).enumerate() {
+        let newest = OVERSAMPLE_TAPS_PER_PHASE + frame;
+        for (phase, sample) in samples.iter_mut().enumerate() {
+            let sum = filter[phase..].iter().step_by(factor).enumerate()
+                .map(|(k, h)| *h * history[newest - k])
+                .sum::<f32>();
+            *sample = sum * factor as f32;
+        }
+    }
+}
+
+/// 抽取：`history` 前 `filter.len() - 1` 个为上一次的输入，之后为本次提高采样率后的数据
+fn downsample(filter: &[f32], factor: usize, history: &[f32], to: &mut [f32]) {
+    let order = filter.len() - 1;
+    for (frame, sample) in to.iter_mut().enumerate() {
+        let newest = order + frame * factor;
+        *sample = filter.iter().enumerate().map(|(j, h)| *h * history[newest - j]).sum();
+    }
+}
+
+/// Kaiser 窗的线性相位低通，`OVERSAMPLE_TAPS_PER_PHASE * factor + 1` 个抽头，直流增益为 1
+fn lowpass(factor: usize) -> Vec<f32> {
+    let len = OVERSAMPLE_TAPS_PER_PHASE * factor + 1;
+    let center = (len - 1) as f64 / 2.0;
+    let cutoff = CUTOFF / factor as f64;
+    let taps = (0..len)
+        .map(|n| {
+            let x = n as f64 - center;
+            let sinc = if x == 0.0 { 1.0 } else { (2.0 * PI * cutoff * x).sin() / (2.0 * PI * cutoff * x) };
+            let ratio = x / center;
+            sinc * bessel_i0(KAISER_BETA * (1.0 - ratio * ratio).sqrt())
+        })
+        .collect::<Vec<_>>();
+    let sum = taps.iter().sum::<f64>();
+    taps.iter().map(|tap| (tap / sum) as f32).collect()
+}
+
+/// 第一类零阶修正贝塞尔函数
+fn bessel_i0(x: f64) -> f64 {
+    let (mut sum, mut term) = (1.0, 1.0);
+    for k in 1..50 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::approx_eq;
+    use crate::automation::Lane;
+    use crate::event::{MidiMessage, Sequencer};
+    use crate::graph_flow::GraphFlowBuilder;
+    use crate::musiblock::{CurveType, Node};
+    use crate::param::{Param, ParamSpec};
+    use crate::subgraph::Subgraph;
+
+    fn sine(freq: f32) -> impl Fn(Time, &mut IOData, usize) {
+        move |time: Time, outputs: &mut IOData, _num_channels: usize| {
+            for (frame, sample) in outputs[0].iter_mut().enumerate() {
+                let t = (time.sample() + frame as u64) as f32 / time.sample_rate() as f32;
+                *sample = (std::f32::consts::TAU * freq * t).sin();
+            }
+        }
+    }
+
+    #[test]
+    fn oversampled_pass_through() {
+        let mut gf = GraphFlowBuilder { buffer_size: 64, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let source = gf.add_block(sine(1000.0)).unwrap();
+        let pass = Oversample::new(|time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
+            assert_eq!(time.sample_rate(), 192000);
+            outputs[0].copy_from_slice(&inputs[0]);
+        }, 4);
+        let pass = gf.add_block(pass).unwrap();
+        gf.connect(source.port(0), pass.port(0)).unwrap();
+        gf.to_output(pass.port(0)).unwrap();
+        assert_eq!(gf.block_latency(&pass).unwrap(), OVERSAMPLE_TAPS_PER_PHASE);
+
+        let (mut input, mut output) = (gf.tap(source.port(0)).unwrap(), gf.tap(pass.port(0)).unwrap());
+        let mut buffer = vec![0.0; 64];
+        for _ in 0..16 {
+            gf.run(64, &mut buffer);
+        }
+        let (mut expected, mut actual) = (vec![0.0; 1024], vec![0.0; 1024]);
+        input.read(&mut expected);
+        output.read(&mut actual);
+        let delay = OVERSAMPLE_TAPS_PER_PHASE;
+        // 跳过滤波器从静音开始的过渡
+        let aligned = actual[delay..].iter().zip(expected.iter()).skip(64);
+        assert!(aligned.map(|(a, e)| (a - e).abs()).all(|error| error < 1e-3));
+    }
+
+    #[test]
+    fn multi_rate_subgraph() {
+        let mut inner = GraphFlowBuilder { num_threads: 0, ..Default::default() }.build();
+        let dc = inner.add_block(|time: Time, outputs: &mut IOData, _num_channels: usize| {
+            assert_eq!(time.sample_rate(), 96000);
+            outputs[0].fill(1.0);
+        }).unwrap();
+        let synth = Subgraph::new(inner).output("out", dc.port(0)).unwrap();
+
+        let mut gf = GraphFlowBuilder { buffer_size: 64, num_channels: 2, num_threads: 0, ..Default::default() }.build();
+        let synth = gf.add_block(Oversample::new(synth, 2)).unwrap();
+        gf.to_output(synth.named("out")).unwrap();
+        let mut buffer = vec![0.0; 64];
+        for _ in 0..2 {
+            gf.run(64, &mut buffer);
+        }
+        assert!(buffer.iter().all(|&sample| approx_eq(sample, 1.0)));
+    }
+
+    #[test]
+    fn automated_inner_param() {
+        struct Knob(Param);
+
+        impl Processor for Knob {
+            fn prepare(&mut self, sample_rate: u32, _max_buffer: usize, _num_channels: usize) {
+                self.0.prepare(sample_rate);
+            }
+
+            fn params(&self) -> Vec<ParamHandle> {
+                vec![self.0.handle()]
+            }
+
+            fn process(&mut self, _time: Time, _inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+                for frame in 0..outputs.num_frames() {
+                    let value = self.0.tick();
+                    outputs.channels_mut(0).for_each(|samples| samples[frame] = value);
+                }
+            }
+        }
+
+        // 轨道每秒上升 1000，即外层每帧上升 1；内部以两倍的采样率逐帧求值
+        let mut gf = GraphFlowBuilder { sample_rate: 1000, buffer_size: 64, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let knob = Oversample::new(Knob(Param::new(ParamSpec::new("level", 0.0, 10000.0, 0.0))), 2);
+        let knob = gf.add_block(knob).unwrap();
+        gf.to_output(knob.port(0)).unwrap();
+        let level = gf.param(&knob, "level").unwrap();
+        let automation = level.automation();
+        automation.set_lane(Lane::from(vec![
+            Node { t: 0.0, v: 0.0, curve: CurveType::Linear, if_hold: false },
+            Node { t: 10.0, v: 10000.0, curve: CurveType::Linear, if_hold: false },
+        ]));
+        automation.set_enabled(true);
+
+        let mut buffer = vec![0.0; 64];
+        for _ in 0..4 {
+            gf.run(64, &mut buffer);
+        }
+        assert!(buffer.windows(2).all(|pair| approx_eq(pair[1] - pair[0], 1.0)));
+    }
+
+    #[test]
+    fn events_align_with_audio() {
+        /// 收到 NOTE ON 后从 `gate` 输出 1，`thru` 原样输出音频输入
+        struct Gate(f32);
+
+        impl Processor for Gate {
+            fn ports(&self) -> PortLayout {
+                PortLayout::new()
+                    .input("midi", SignalKind::Event)
+                    .input("in", SignalKind::Audio)
+                    .output("gate", SignalKind::Audio)
+                    .output("thru", SignalKind::Audio)
+            }
+
+            fn process(&mut self, _time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+                for frame in 0..outputs.num_frames() {
+                    if inputs.events(0).at_frame(frame).next().is_some() {
+                        self.0 = 1.0;
+                    }
+                    outputs[0][frame] = self.0;
+                }
+                outputs[1].copy_from_slice(&inputs[1]);
+            }
+        }
+
+        // 第 100 帧同时到达的事件与音频阶跃，经过过采样后应当在同一帧出现
+        let mut gf = GraphFlowBuilder { buffer_size: 64, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let note = MidiMessage::NoteOn { channel: 0, key: 60, velocity: 100 };
+        let sequencer = gf.add_block(Sequencer::new(vec![(100, note)], 1000)).unwrap();
+        let step = gf.add_block(|time: Time, outputs: &mut IOData, _num_channels: usize| {
+            for (frame, sample) in outputs[0].iter_mut().enumerate() {
+                *sample = if time.sample() + frame as u64 >= 100 { 1.0 } else { 0.0 };
+            }
+        }).unwrap();
+        let gate = gf.add_block(Oversample::new(Gate(0.0), 2)).unwrap();
+        gf.connect(sequencer.named("events"), gate.named("midi")).unwrap();
+        gf.connect(step.port(0), gate.named("in")).unwrap();
+        gf.to_output(gate.named("gate")).unwrap();
+        gf.to_output(gate.named("thru")).unwrap();
+
+        let (mut from_event, mut from_audio) = (gf.tap(gate.named("gate")).unwrap(), gf.tap(gate.named("thru")).unwrap());
+        let mut buffer = vec![0.0; 64];
+        for _ in 0..4 {
+            gf.run(64, &mut buffer);
+        }
+        let (mut event_out, mut audio_out) = (vec![0.0; 256], vec![0.0; 256]);
+        from_event.read(&mut event_out);
+        from_audio.read(&mut audio_out);
+        let onset = |samples: &[f32]| samples.iter().position(|&sample| sample > 0.5);
+        assert_eq!(onset(&event_out), Some(100 + OVERSAMPLE_TAPS_PER_PHASE));
+        assert_eq!(onset(&audio_out), Some(100 + OVERSAMPLE_TAPS_PER_PHASE));
+    }
+
+    #[test]
+    fn anti_aliasing() {
+        // 内部以 192 kHz 产生 30 kHz 的正弦，高于 48 kHz 的奈奎斯特频率，应当被滤除
+        let mut gf = GraphFlowBuilder { buffer_size: 64, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let tone = gf.add_block(Oversample::new(sine(30000.0), 4)).unwrap();
+        gf.to_output(tone.port(0)).unwrap();
+
+        let mut buffer = vec![0.0; 64];
+        for _ in 0..4 {
+            gf.run(64, &mut buffer);
+        }
+        assert!(buffer.iter().all(|sample| sample.abs() < 1e-3));
+    }
+}
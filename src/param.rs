@@ -0,0 +1,237 @@
+//! 块参数
+//!
+//! 块在 `Processor::params` 中声明参数，UI 或网络线程通过 `ParamHandle` 无锁地设置目标值，
+//! 音频线程通过 `Param::tick` 逐帧取值，目标变化时在平滑时长内线性过渡，避免爆音。
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::automation::{Automation, Lane};
+
+/// 默认的平滑时长（秒）
+pub const DEFAULT_SMOOTHING_SECS: f32 = 0.02;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamSpec {
+    pub name: String,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub unit: String,
+}
+
+impl ParamSpec {
+    pub fn new(name: &str, min: f32, max: f32, default: f32) -> Self {
+        assert!(min <= max, "Param min must not be greater than max");
+        ParamSpec { name: name.to_string(), min, max, default: default.clamp(min, max), unit: String::new() }
+    }
+
+    pub fn unit(mut self, unit: &str) -> Self {
+        self.unit = unit.to_string();
+        self
+    }
+
+    pub fn clamp(&self, value: f32) -> f32 {
+        value.clamp(self.min, self.max)
+    }
+}
+
+struct Shared {
+    spec: ParamSpec,
+    value: AtomicU32,  // f32 的位
+    automation: Automation,
+}
+
+/// 参数的无锁句柄，可以在任意线程读写
+#[derive(Clone)]
+pub struct ParamHandle(Arc<Shared>);
+
+impl ParamHandle {
+    pub fn spec(&self) -> &ParamSpec {
+        &self.0.spec
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0.spec.name
+    }
+
+    /// 目标值，音频线程上的值会平滑地过渡到它
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.value.load(Ordering::Relaxed))
+    }
+
+    /// 设置目标值，超出范围时截断；录制自动化时同时写入轨道
+    pub fn set(&self, value: f32) {
+        let value = self.0.spec.clamp(value);
+        self.0.value.store(value.to_bits(), Ordering::Relaxed);
+        if self.0.automation.is_recording() {
+            self.0.automation.record(value);
+        }
+    }
+
+    pub fn automation(&self) -> &Automation {
+        &self.0.automation
+    }
+
+    /// 归一化到 `0..=1` 的目标值，用于滑块等控件
+    pub fn normalized(&self) -> f32 {
+        let spec = self.spec();
+        if spec.max == spec.min {
+            return 0.0;
+        }
+        (self.get() - spec.min) / (spec.max - spec.min)
+    }
+
+    pub fn set_normalized(&self, normalized: f32) {
+        let spec = self.spec();
+        self.set(spec.min + normalized.clamp(0.0, 1.0) * (spec.max - spec.min));
+    }
+
+    pub fn set_default(&self) {
+        self.set(self.0.spec.default);
+    }
+}
+
+impl fmt::Debug for ParamHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {}{}", self.name(), self.get(), self.spec().unit)
+    }
+}
+
+/// 音频线程一侧的参数
+pub struct Param {
+    handle: ParamHandle,
+    smoothing_secs: f32,
+    smoothing_frames: usize,
+    current: f32,
+    target: f32,
+    step: f32,
+    remaining: usize,
+    // 自动化：音频线程持有的轨道及其版本，当前缓冲区起点与帧偏移
+    lane: Arc<Lane>,
+    version: u64,
+    position: u64,
+    frame: u64,
+}
+
+impl Param {
+    pub fn new(spec: ParamSpec) -> Self {
+        let default = spec.default;
+        Param {
+            handle: ParamHandle(Arc::new(Shared {
+                spec,
+                value: AtomicU32::new(default.to_bits()),
+                automation: Automation::default(),
+            })),
+            smoothing_secs: DEFAULT_SMOOTHING_SECS,
+            smoothing_frames: 1,
+            current: default,
+            target: default,
+            step: 0.0,
+            remaining: 0,
+            lane: Arc::new(Lane::new()),
+            version: 0,
+            position: 0,
+            frame: 0,
+        }
+    }
+
+    /// 平滑时长（秒），在下一次 `prepare` 时生效
+    pub fn with_smoothing(mut self, secs: f32) -> Self {
+        self.smoothing_secs = secs.max(0.0);
+        self
+    }
+
+    pub fn handle(&self) -> ParamHandle {
+        self.handle.clone()
+    }
+
+    /// 在 `Processor::prepare` 中调用
+    pub fn prepare(&mut self, sample_rate: u32) {
+        self.smoothing_frames = ((sample_rate as f32 * self.smoothing_secs) as usize).max(1);
+    }
+
+    /// 跳过平滑，直接到达目标值
+    pub fn reset(&mut self) {
+        self.target = self.handle.get();
+        self.current = self.target;
+        self.remaining = 0;
+    }
+
+    /// 前进一帧并返回平滑后的值；启用自动化且不在录制时返回轨道在这一帧的值
+    pub fn tick(&mut self) -> f32 {
+        if let Some(value) = self.automated() {
+            self.current = value;
+            self.target = value;
+            self.remaining = 0;
+            // 让 UI 看到自动化的值，关闭自动化时也不会跳变
+            self.handle.0.value.store(value.to_bits(), Ordering::Relaxed);
+            return value;
+        }
+        let target = self.handle.get();
+        if target != self.target {
+            self.target = target;
+            self.remaining = self.smoothing_frames;
+            self.step = (target - self.current) / self.smoothing_frames as f32;
+        }
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.current = if self.remaining == 0 { self.target } else { self.current + self.step };
+        }
+        self.current
+    }
+
+    fn automated(&mut self) -> Option<f32> {
+        let automation = &self.handle.0.automation;
+        if !automation.is_enabled() || automation.is_recording() {
+            return None;
+        }
+        let (position, sample_rate) = automation.position();
+        if position != self.position {
+            self.position = position;
+            self.frame = 0;
+        }
+        if automation.version() != self.version {
+            if let Some(version) = automation.try_swap_lane(&mut self.lane) {
+                self.version = version;
+            }
+        }
+        let secs = ((self.position + self.frame) as f64 / sample_rate as f64) as f32;
+        self.frame += 1;
+        self.lane.value_at(secs).map(|value| self.handle.0.spec.clamp(value))
+    }
+
+    /// 当前（平滑中）的值，不前进
+    pub fn value(&self) -> f32 {
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smoothed_param() {
+        let mut param = Param::new(ParamSpec::new("gain", 0.0, 10.0, 0.0).unit("dB")).with_smoothing(0.004);
+        param.prepare(1000);
+        let handle = param.handle();
+        assert_eq!(param.tick(), 0.0);
+
+        handle.set(4.0);
+        let ramp = (0..5).map(|_| param.tick()).collect::<Vec<_>>();
+        assert_eq!(ramp, [1.0, 2.0, 3.0, 4.0, 4.0]);
+
+        handle.set(20.0);
+        assert_eq!(handle.get(), 10.0);
+        assert_eq!(handle.normalized(), 1.0);
+        param.reset();
+        assert_eq!(param.value(), 10.0);
+
+        handle.set_normalized(0.5);
+        handle.set_default();
+        assert_eq!(handle.get(), 0.0);
+        assert_eq!(format!("{:?}", handle), "gain = 0dB");
+    }
+}
@@ -0,0 +1,443 @@
+//! 复音：为每个音符实例化一份声部模板
+//!
+//! `Poly` 是一个容器块，声部模板是一个 `Subgraph`。模板可以暴露名为 `pitch`（Hz）、`gate`
+//! （0 或 1）与 `velocity`（0 到 1）的输入，由容器按各声部的音符写入；名为 `midi` 的事件输入
+//! 收到本声部的音符事件与所有控制事件。模板的其他输入成为容器的输入，广播给所有声部；
+//! 各声部的输出相加后作为容器的输出。
+//!
+//! 所有声部在创建时按 `max_voices` 建好，音频线程上只是启用与回收，不会分配。音符松开后，
+//! 声部的输出有一整个缓冲区低于 `SILENCE` 时被回收。声部用尽时按 `VoiceStealing` 抢占：
+//! 被抢占的声部不重置，门信号先落下一个控制间隔再抬起，包络从当前电平重新起音，避免爆音。
+//! 声部的参数各自独立，需要统一调节时通过广播的输入端口。
+
+use crate::block::{IOData, PortLayout, Processor, SignalKind, Time, DEFAULT_CONTROL_INTERVAL};
+use crate::event::{Event, EventBuffer, MidiMessage, EVENT_CAPACITY};
+use crate::subgraph::Subgraph;
+
+/// 音符松开后声部输出低于这个幅度（约 -80 dB）一整个缓冲区即被回收
+pub const SILENCE: f32 = 1e-4;
+
+/// 声部用尽时如何处理新的音符
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VoiceStealing {
+    /// 优先抢占已经松开的声部，其次是最早开始的
+    #[default]
+    Oldest,
+    /// 抢占上一个缓冲区输出最小的声部
+    Quietest,
+    /// 不抢占，忽略新的音符
+    None,
+}
+
+/// 声部模板每个输入的来源
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    Pitch,
+    Gate,
+    Velocity,
+    Midi,
+    /// 容器的输入端口
+    Input(usize),
+}
+
+/// 声部当前的音符
+#[derive(Debug, Clone, Copy, Default)]
+struct Note {
+    pitch: f32,
+    gate: f32,
+    velocity: f32,
+}
+
+impl Note {
+    fn apply(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::NoteOn { key, velocity, .. } => {
+                *self = Note { pitch: key_to_freq(key), gate: 1.0, velocity: velocity as f32 / 127.0 };
+            }
+            MidiMessage::NoteOff { .. } => self.gate = 0.0,
+            _ => {}
+        }
+    }
+
+    fn value(&self, source: Source) -> f32 {
+        match source {
+            Source::Pitch => self.pitch,
+            Source::Gate => self.gate,
+            Source::Velocity => self.velocity,
+            Source::Midi | Source::Input(_) => unreachable!(),
+        }
+    }
+}
+
+fn key_to_freq(key: u8) -> f32 {
+    440.0 * 2.0f32.powf((key as f32 - 69.0) / 12.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VoiceState {
+    Idle,
+    Held,
+    Released,
+}
+
+struct Voice {
+    graph: Subgraph,
+    inputs: IOData,
+    outputs: IOData,
+    state: VoiceState,
+    /// 正在演奏的 (通道, 音高)
+    key: (u8, u8),
+    /// 开始演奏的次序，越小越早
+    age: u64,
+    /// 本缓冲区开始时的音符
+    note: Note,
+    /// 还没到时间的事件，帧偏移相对于本缓冲区
+    pending: EventBuffer,
+    /// 本缓冲区的事件
+    events: EventBuffer,
+    /// 上一个缓冲区输出的峰值
+    peak: f32,
+}
+
+impl Voice {
+    /// 写入模板的输入：音符按事件逐帧（控制端口逐个控制间隔）变化，其余来自容器的输入
+    fn write_inputs(&mut self, sources: &[Source], inputs: &IOData) {
+        let control_interval = self.inputs.control_interval();
+        for (port, source) in sources.iter().enumerate() {
+            match *source {
+                Source::Input(from) => match inputs.kind(from) {
+                    SignalKind::Event => {
+                        let events = self.inputs.events_mut(port);
+                        events.clear();
+                        events.merge(inputs.events(from));
+                    }
+                    _ => self.inputs[port].copy_from_slice(&inputs[from]),
+                },
+                Source::Midi => {
+                    let events = self.inputs.events_mut(port);
+                    events.clear();
+                    events.merge(&self.events);
+                }
+                source => {
+                    let mut note = self.note;
+                    let mut events = self.events.iter().peekable();
+                    match self.inputs.kind(port) {
+                        SignalKind::Control => {
+                            for (slot, value) in self.inputs[port].iter_mut().enumerate() {
+                                let end = (slot + 1) * control_interval;
+                                while let Some(event) = events.next_if(|event| event.frame < end) {
+                                    note.apply(event.message);
+                                }
+                                *value = note.value(source);
+                            }
+                        }
+                        _ => {
+                            for frame in 0..self.inputs.num_frames() {
+                                while let Some(event) = events.next_if(|event| event.frame <= frame) {
+                                    note.apply(event.message);
+                                }
+                                let value = note.value(source);
+                                self.inputs.channels_mut(port).for_each(|samples| samples[frame] = value);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct Poly {
+    voices: Vec<Voice>,
+    stealing: VoiceStealing,
+    ports: PortLayout,
+    /// 与声部模板的输入一一对应
+    sources: Vec<Source>,
+    num_channels: usize,
+    max_buffer: usize,
+    control_interval: usize,
+    next_age: u64,
+}
+
+impl Poly {
+    /// 用 `template` 建好 `max_voices` 个声部，容器的端口按模板决定
+    pub fn new(max_voices: usize, template: impl Fn() -> Subgraph) -> Self {
+        assert!(max_voices > 0, "Poly needs at least one voice");
+        let voices = (0..max_voices).map(|_| Voice {
+            graph: template(),
+            inputs: IOData::new(0, 0),
+            outputs: IOData::new(0, 0),
+            state: VoiceState::Idle,
+            key: (0, 0),
+            age: 0,
+            note: Note::default(),
+            pending: EventBuffer::with_capacity(EVENT_CAPACITY),
+            events: EventBuffer::with_capacity(EVENT_CAPACITY),
+            peak: 0.0,
+        }).collect::<Vec<_>>();
+
+        let template_ports = voices[0].graph.ports();
+        let mut ports = PortLayout::new().input("midi", SignalKind::Event);
+        let sources = template_ports.inputs.iter().map(|spec| match (spec.name.as_str(), spec.kind) {
+            ("midi", SignalKind::Event) => Source::Midi,
+            ("pitch", kind) if kind != SignalKind::Event => Source::Pitch,
+            ("gate", kind) if kind != SignalKind::Event => Source::Gate,
+            ("velocity", kind) if kind != SignalKind::Event => Source::Velocity,
+            _ => {
+                ports.inputs.push(spec.clone());
+                Source::Input(ports.inputs.len() - 1)
+            }
+        }).collect();
+        ports.outputs = template_ports.outputs;
+
+        Poly {
+            voices,
+            stealing: VoiceStealing::default(),
+            ports,
+            sources,
+            num_channels: 0,
+            max_buffer: 0,
+            control_interval: DEFAULT_CONTROL_INTERVAL,
+            next_age: 0,
+        }
+    }
+
+    pub fn with_stealing(mut self, stealing: VoiceStealing) -> Self {
+        self.stealing = stealing;
+        self
+    }
+
+    pub fn max_voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// 按外层的控制间隔分配声部的输入输出，在 `prepare` 中调用
+    fn allocate(&mut self) {
+        let (max_buffer, num_channels, control_interval) = (self.max_buffer, self.num_channels, self.control_interval);
+        for voice in self.voices.iter_mut() {
+            let ports = voice.graph.ports();
+            voice.inputs = IOData::with_capacity(&ports.inputs, max_buffer, max_buffer, num_channels, control_interval);
+            voice.outputs = IOData::with_capacity(&ports.outputs, max_buffer, max_buffer, num_channels, control_interval);
+        }
+    }
+
+    /// 为新的音符选择声部，没有可用的声部时返回 `None`
+    fn voice_for(&self, key: (u8, u8)) -> Option<usize> {
+        // 同一个音符再次按下时沿用原来的声部
+        if let Some(index) = self.voices.iter().position(|voice| voice.state != VoiceState::Idle && voice.key == key) {
+            return Some(index);
+        }
+        if let Some(index) = self.voices.iter().position(|voice| voice.state == VoiceState::Idle) {
+            return Some(index);
+        }
+        let voices = self.voices.iter().enumerate();
+        match self.stealing {
+            VoiceStealing::Oldest => voices.min_by_key(|(_, voice)| (voice.state == VoiceState::Held, voice.age)),
+            VoiceStealing::Quietest => voices.min_by(|(_, a), (_, b)| a.peak.total_cmp(&b.peak)),
+            VoiceStealing::None => None,
+        }.map(|(index, _)| index)
+    }
+
+    /// 把容器收到的事件分给各声部
+    fn dispatch(&mut self, event: Event) {
+        match event.message {
+            MidiMessage::NoteOn { channel, key, .. } => {
+                let Some(index) = self.voice_for((channel, key)) else {
+                    return;
+                };
+                let control_interval = self.control_interval;
+                let voice = &mut self.voices[index];
+                if voice.state == VoiceState::Idle {
+                    voice.graph.reset();
+                    voice.note = Note::default();
+                    voice.pending.push(event);
+                } else {
+                    // 被抢占或重复按下：门信号落下一个控制间隔后重新起音
+                    let (channel, key) = voice.key;
+                    voice.pending.push(Event { frame: event.frame, message: MidiMessage::NoteOff { channel, key, velocity: 0 } });
+                    voice.pending.push(Event { frame: event.frame + control_interval, ..event });
+                }
+                voice.state = VoiceState::Held;
+                voice.key = (channel, key);
+                voice.age = self.next_age;
+                self.next_age += 1;
+            }
+            MidiMessage::NoteOff { channel, key, .. } => {
+                let held = self.voices.iter_mut()
+                    .find(|voice| voice.state == VoiceState::Held && voice.key == (channel, key));
+                if let Some(voice) = held {
+                    // 不早于还没起音的 NOTE ON
+                    let frame = voice.pending.iter().last().map_or(event.frame, |last| last.frame.max(event.frame));
+                    voice.pending.push(Event { frame, ..event });
+                    voice.state = VoiceState::Released;
+                }
+            }
+            _ => {
+                for voice in self.voices.iter_mut().filter(|voice| voice.state != VoiceState::Idle) {
+                    voice.pending.push(event);
+                }
+            }
+        }
+    }
+}
+
+impl Processor for Poly {
+    fn prepare(&mut self, sample_rate: u32, max_buffer: usize, num_channels: usize) {
+        self.num_channels = num_channels;
+        self.max_buffer = max_buffer;
+        self.allocate();
+        for voice in self.voices.iter_mut() {
+            voice.graph.prepare(sample_rate, max_buffer, num_channels);
+        }
+    }
+
+    fn set_control_interval(&mut self, control_interval: usize) {
+        self.control_interval = control_interval;
+        for voice in self.voices.iter_mut() {
+            voice.graph.set_control_interval(control_interval);
+        }
+    }
+
+    fn reset(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.graph.reset();
+            voice.state = VoiceState::Idle;
+            voice.note = Note::default();
+            voice.pending.clear();
+            voice.peak = 0.0;
+        }
+    }
+
+    fn ports(&self) -> PortLayout {
+        self.ports.clone()
+    }
+
+    fn latency(&self) -> usize {
+        self.voices[0].graph.latency()
+    }
+
+    fn process(&mut self, time: Time, inputs: &IOData, outputs: &mut IOData, num_channels: usize) {
+        debug_assert_eq!(self.control_interval, inputs.control_interval(), "Poly was prepared for another control interval");
+        for event in inputs.events(0) {
+            self.dispatch(*event);
+        }
+        for port in 0..outputs.port_len() {
+            outputs[port].fill(0.0);
+        }
+        outputs.clear_events();
+
+        let num_frames = outputs.num_frames();
+        let buffer_size = outputs.buffer_size();
+        for voice in self.voices.iter_mut().filter(|voice| voice.state != VoiceState::Idle) {
+            if voice.inputs.buffer_size() != buffer_size {
+                voice.inputs.set_buffer_size(buffer_size, num_channels);
+                voice.outputs.set_buffer_size(buffer_size, num_channels);
+            }
+            voice.events.clear();
+            voice.pending.drain_before(num_frames, &mut voice.events);
+            voice.write_inputs(&self.sources, inputs);
+            for port in 0..voice.outputs.port_len() {
+                voice.outputs[port].fill(0.0);
+            }
+            voice.outputs.clear_events();
+            voice.graph.process(time, &voice.inputs, &mut voice.outputs, num_channels);
+
+            voice.peak = 0.0;
+            for port in 0..outputs.port_len() {
+                match outputs.kind(port) {
+                    SignalKind::Event => outputs.events_mut(port).merge(voice.outputs.events(port)),
+                    kind => {
+                        let samples = outputs[port].iter_mut().zip(voice.outputs[port].iter());
+                        for (output, sample) in samples {
+                            *output += *sample;
+                            if kind == SignalKind::Audio {
+                                voice.peak = voice.peak.max(sample.abs());
+                            }
+                        }
+                    }
+                }
+            }
+            for event in voice.events.iter() {
+                voice.note.apply(event.message);
+            }
+            if voice.state == VoiceState::Released && voice.peak < SILENCE && voice.pending.is_empty() {
+                voice.state = VoiceState::Idle;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Sequencer;
+    use crate::graph_flow::{GraphFlow, GraphFlowBuilder};
+
+    /// 门信号抬起时输出力度，落下后每帧下降 0.25
+    struct Envelope(f32);
+
+    impl Processor for Envelope {
+        fn ports(&self) -> PortLayout {
+            PortLayout::new()
+                .input("gate", SignalKind::Control)
+                .input("velocity", SignalKind::Control)
+                .output("out", SignalKind::Audio)
+        }
+
+        fn process(&mut self, _time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+            for (frame, sample) in outputs[0].iter_mut().enumerate() {
+                self.0 = if inputs[0][frame] > 0.0 { inputs[1][frame] } else { (self.0 - 0.25).max(0.0) };
+                *sample = self.0;
+            }
+        }
+    }
+
+    fn builder() -> GraphFlowBuilder {
+        GraphFlowBuilder { buffer_size: 16, num_channels: 1, num_threads: 0, control_interval: 1, ..Default::default() }
+    }
+
+    fn voice() -> Subgraph {
+        let mut gf = builder().build();
+        let envelope = gf.add_block(Envelope(0.0)).unwrap();
+        Subgraph::new(gf)
+            .input("gate", envelope.named("gate")).unwrap()
+            .input("velocity", envelope.named("velocity")).unwrap()
+            .output("out", envelope.named("out")).unwrap()
+    }
+
+    /// 两个声部的复音块，按帧播放 `notes`，返回每帧的输出
+    fn play(stealing: VoiceStealing, notes: Vec<(u64, MidiMessage)>, num_buffers: usize) -> Vec<f32> {
+        let mut gf: GraphFlow = builder().build();
+        let sequencer = gf.add_block(Sequencer::new(notes, 1 << 20)).unwrap();
+        let poly = gf.add_block(Poly::new(2, voice).with_stealing(stealing)).unwrap();
+        gf.connect(sequencer.named("events"), poly.named("midi")).unwrap();
+        gf.to_output(poly.named("out")).unwrap();
+        let mut output = vec![0.0; 16 * num_buffers];
+        gf.run(output.len() as u32, &mut output);
+        output
+    }
+
+    fn on(key: u8) -> MidiMessage {
+        MidiMessage::NoteOn { channel: 0, key, velocity: 127 }
+    }
+
+    fn off(key: u8) -> MidiMessage {
+        MidiMessage::NoteOff { channel: 0, key, velocity: 0 }
+    }
+
+    #[test]
+    fn voice_stealing() {
+        // 第三个音符抢占最早的声部：门信号落下一帧后重新起音，之后松开第一个音符不起作用
+        let output = play(VoiceStealing::Oldest, vec![(0, on(60)), (2, on(62)), (4, on(64)), (8, off(64)), (10, off(60))], 1);
+        assert_eq!(output, [1.0, 1.0, 2.0, 2.0, 1.75, 2.0, 2.0, 2.0, 1.75, 1.5, 1.25, 1.0, 1.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn voice_limit_and_release() {
+        // 不抢占：声部用尽时忽略新的音符，松开的声部静音一整个缓冲区后才能再用
+        let output = play(VoiceStealing::None, vec![(0, on(60)), (2, on(62)), (4, off(60)), (20, on(64)), (40, on(67))], 3);
+        assert_eq!(output[..8], [1.0, 1.0, 2.0, 2.0, 1.75, 1.5, 1.25, 1.0]);
+        assert!(output[8..40].iter().all(|&sample| sample == 1.0));
+        assert!(output[40..].iter().all(|&sample| sample == 2.0));
+    }
+}
@@ -0,0 +1,159 @@
+//! DSP 负载统计
+//!
+//! 运行计划记录每个块每次 `process` 的耗时，图流记录整个运行计划的耗时，并与缓冲区的
+//! 实时长度比较：超过时记为一次过载。统计量是原子的，由处理该块的线程写入。
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::block::BlockId;
+
+/// 一段耗时的最小值、平均值与最大值
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timing {
+    pub min: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+    pub runs: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub blocks: HashMap<BlockId, Timing>,
+    /// 整个运行计划（含等待工作线程）的耗时
+    pub graph: Timing,
+    /// 最近一次运行的耗时占缓冲区实时长度的比例，大于 1 即过载
+    pub load: f32,
+    pub peak_load: f32,
+    pub overruns: u64,
+}
+
+/// 纳秒计的耗时统计
+pub(crate) struct Stats {
+    min: AtomicU64,
+    max: AtomicU64,
+    total: AtomicU64,
+    runs: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            runs: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Stats {
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos() as u64;
+        self.min.fetch_min(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+        self.total.fetch_add(nanos, Ordering::Relaxed);
+        self.runs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn timing(&self) -> Timing {
+        let runs = self.runs.load(Ordering::Relaxed);
+        if runs == 0 {
+            return Timing::default();
+        }
+        Timing {
+            min: Duration::from_nanos(self.min.load(Ordering::Relaxed)),
+            mean: Duration::from_nanos(self.total.load(Ordering::Relaxed) / runs),
+            max: Duration::from_nanos(self.max.load(Ordering::Relaxed)),
+            runs,
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.min.store(u64::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.runs.store(0, Ordering::Relaxed);
+    }
+}
+
+/// 图流一级的统计，由运行图流的线程写入，拆分后与 `Engine` 共享
+#[derive(Default)]
+pub(crate) struct GraphStats {
+    pub(crate) stats: Stats,
+    load: AtomicU32,  // f32 的位
+    peak_load: AtomicU32,
+    overruns: AtomicU64,
+}
+
+impl GraphStats {
+    /// 记录一次运行，`budget` 为缓冲区的实时长度
+    pub(crate) fn record(&self, elapsed: Duration, budget: Duration) {
+        self.stats.record(elapsed);
+        let load = elapsed.as_secs_f32() / budget.as_secs_f32().max(f32::MIN_POSITIVE);
+        self.load.store(load.to_bits(), Ordering::Relaxed);
+        if load > self.peak_load() {
+            self.peak_load.store(load.to_bits(), Ordering::Relaxed);
+        }
+        if elapsed > budget {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn load(&self) -> f32 {
+        f32::from_bits(self.load.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn peak_load(&self) -> f32 {
+        f32::from_bits(self.peak_load.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.stats.reset();
+        self.load.store(0, Ordering::Relaxed);
+        self.peak_load.store(0, Ordering::Relaxed);
+        self.overruns.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::block::{IOData, Time};
+    use crate::graph_flow::GraphFlowBuilder;
+
+    #[test]
+    fn profile_blocks() {
+        // 每个缓冲区 10 帧，即 0.1 毫秒的实时长度
+        let mut gf = GraphFlowBuilder { sample_rate: 100_000, buffer_size: 10, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let slow = gf.add_block(|_time: Time, _outputs: &mut IOData, _num_channels: usize| {
+            std::thread::sleep(Duration::from_millis(1));
+        }).unwrap();
+        let fast = gf.add_block(|_time: Time, _outputs: &mut IOData, _num_channels: usize| {}).unwrap();
+        gf.to_output(slow.port(0)).unwrap();
+
+        let mut output = vec![0.0; 10];
+        for _ in 0..3 {
+            gf.run(10, &mut output);
+        }
+        let profile = gf.profile();
+        let timing = profile.blocks[&slow];
+        assert_eq!(timing.runs, 3);
+        assert!(timing.min >= Duration::from_millis(1));
+        assert!(timing.min <= timing.mean && timing.mean <= timing.max);
+        assert_eq!(profile.blocks[&fast].runs, 3);
+        assert!(profile.graph.min >= timing.min);
+        assert_eq!(profile.overruns, 3);
+        assert!(profile.load > 1.0 && profile.peak_load >= profile.load);
+
+        gf.reset_profile();
+        let profile = gf.profile();
+        assert_eq!((profile.blocks[&slow].runs, profile.graph.runs, profile.overruns), (0, 0, 0));
+    }
+}
@@ -0,0 +1,426 @@
+//! 工程的保存与加载
+//!
+//! 闭包无法序列化，因此块通过 `Registry` 按类型名和可序列化的配置创建，工程文件（JSON）
+//! 只记录类型名、配置、参数值与自动化轨道、块的模式、连接与输出及其独奏。加载时用同一个
+//! 注册表重建图流，块的 id 保持不变。
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::block::{Block, BlockId, BlockMarker, IntoBlock, Port, PortDirection, PortRef};
+use crate::automation::Lane;
+use crate::graph_flow::{EdgeKind, GraphError, GraphFlow, GraphFlowBuilder};
+use crate::musiblock::Node;
+use crate::switch::BlockMode;
+
+/// 块在注册表中的类型名与创建它的配置
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockType {
+    pub name: String,
+    pub config: Value,
+}
+
+#[derive(Debug)]
+pub enum ProjectError {
+    Graph(GraphError),
+    /// 工程文件或块的配置格式不正确
+    Format(serde_json::Error),
+    Io(std::io::Error),
+    /// 注册表中没有该类型
+    UnknownType(String),
+    /// 块不是由注册表创建的，无法保存
+    Unregistered(BlockId),
+    /// 工程文件中有重复的块 id
+    DuplicateBlock(BlockId),
+}
+
+impl std::fmt::Display for ProjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProjectError::Graph(err) => write!(f, "{}", err),
+            ProjectError::Format(err) => write!(f, "invalid project: {}", err),
+            ProjectError::Io(err) => write!(f, "{}", err),
+            ProjectError::UnknownType(name) => write!(f, "unknown block type {:?}", name),
+            ProjectError::Unregistered(block_id) => write!(f, "block {:?} was not created by a registry", block_id),
+            ProjectError::DuplicateBlock(block_id) => write!(f, "duplicate block {:?}", block_id),
+        }
+    }
+}
+
+impl std::error::Error for ProjectError {}
+
+impl From<GraphError> for ProjectError {
+    fn from(err: GraphError) -> Self {
+        ProjectError::Graph(err)
+    }
+}
+
+impl From<serde_json::Error> for ProjectError {
+    fn from(err: serde_json::Error) -> Self {
+        ProjectError::Format(err)
+    }
+}
+
+impl From<std::io::Error> for ProjectError {
+    fn from(err: std::io::Error) -> Self {
+        ProjectError::Io(err)
+    }
+}
+
+type Factory = Box<dyn Fn(Value) -> Result<Block, ProjectError> + Send + Sync>;
+
+/// 块类型注册表：类型名 -> 由配置创建块的工厂
+#[derive(Default)]
+pub struct Registry {
+    factories: HashMap<String, Factory>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 注册块类型，同名的类型会被替换
+    pub fn register<C, M, B>(&mut self, name: &str, factory: impl Fn(C) -> B + Send + Sync + 'static)
+    where
+        C: DeserializeOwned,
+        M: BlockMarker,
+        B: IntoBlock<M>,
+    {
+        let factory = move |config: Value| Ok(factory(serde_json::from_value(config)?).into_block());
+        self.factories.insert(name.to_string(), Box::new(factory));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.contains_key(name)
+    }
+
+    /// 创建块并记录其类型，这样的块才能被保存
+    pub fn create(&self, name: &str, config: impl Serialize) -> Result<Block, ProjectError> {
+        self.create_from(BlockType { name: name.to_string(), config: serde_json::to_value(config)? })
+    }
+
+    fn create_from(&self, block_type: BlockType) -> Result<Block, ProjectError> {
+        let factory = self.factories.get(&block_type.name)
+            .ok_or_else(|| ProjectError::UnknownType(block_type.name.clone()))?;
+        let mut block = factory(block_type.config.clone())?;
+        block.block_type = Some(block_type);
+        Ok(block)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockEntry {
+    pub id: BlockId,
+    #[serde(rename = "type")]
+    pub block_type: String,
+    #[serde(default)]
+    pub config: Value,
+    /// 参数名 -> 值
+    #[serde(default)]
+    pub params: BTreeMap<String, f32>,
+    /// 参数名 -> 自动化轨道，只记录启用了或有断点的参数
+    #[serde(default)]
+    pub automation: BTreeMap<String, AutomationEntry>,
+    #[serde(default)]
+    pub mode: BlockMode,
+}
+
+/// 参数的自动化轨道，录制状态不保存
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AutomationEntry {
+    pub enabled: bool,
+    pub nodes: Vec<Node>,
+}
+
+/// 按名字记录的端口
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Endpoint {
+    pub block: BlockId,
+    pub port: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Connection {
+    pub from: Endpoint,
+    pub to: Endpoint,
+    #[serde(default)]
+    pub kind: EdgeKind,
+}
+
+/// 工程文件的内容
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Project {
+    pub sample_rate: u32,
+    pub buffer_size: u32,
+    pub num_channels: usize,
+    pub control_interval: usize,
+    pub blocks: Vec<BlockEntry>,
+    pub connections: Vec<Connection>,
+    pub outputs: Vec<Endpoint>,
+    /// 独奏的输出，都在 `outputs` 中
+    #[serde(default)]
+    pub solos: Vec<Endpoint>,
+}
+
+impl Project {
+    /// 记录图流的当前状态，所有块都必须由注册表创建
+    pub fn capture(gf: &GraphFlow) -> Result<Self, ProjectError> {
+        let block_ids = gf.block_ids();
+        let endpoint = |port: Port, direction| Endpoint {
+            block: port.block_id,
+            port: gf.spec(port, direction).name.clone(),
+        };
+
+        let blocks = block_ids.iter().map(|block_id| {
+            let block = gf.get_block(block_id)?;
+            let block_type = block.block_type.as_ref().ok_or(ProjectError::Unregistered(*block_id))?;
+            let automation = block.params.iter().filter_map(|param| {
+                let automation = param.automation();
+                let lane = automation.lane();
+                (automation.is_enabled() || !lane.nodes().is_empty()).then(|| (
+                    param.name().to_string(),
+                    AutomationEntry { enabled: automation.is_enabled(), nodes: lane.nodes().to_vec() },
+                ))
+            }).collect();
+            Ok(BlockEntry {
+                id: *block_id,
+                block_type: block_type.name.clone(),
+                config: block_type.config.clone(),
+                params: block.params.iter().map(|param| (param.name().to_string(), param.get())).collect(),
+                automation,
+                mode: block.mode.get(),
+            })
+        }).collect::<Result<Vec<_>, ProjectError>>()?;
+
+        let connections = gf.edges().into_iter().map(|edge| Connection {
+            from: endpoint(edge.from, PortDirection::Output),
+            to: endpoint(edge.to, PortDirection::Input),
+            kind: edge.kind,
+        }).collect();
+
+        // 输出没有顺序，按块的添加顺序与端口排列，保证同一个图流的输出相同
+        let mut outputs = gf.outputs().iter().copied().collect::<Vec<_>>();
+        outputs.sort_by_key(|port| (block_ids.iter().position(|id| *id == port.block_id), port.port));
+        let solos = outputs.iter()
+            .filter(|port| gf.solo(**port).is_ok_and(|solo| solo.get()))
+            .map(|port| endpoint(*port, PortDirection::Output))
+            .collect();
+        let outputs = outputs.into_iter().map(|port| endpoint(port, PortDirection::Output)).collect();
+
+        Ok(Project {
+            sample_rate: gf.sample_rate(),
+            buffer_size: gf.buffer_size(),
+            num_channels: gf.num_channels(),
+            control_interval: gf.control_interval(),
+            blocks,
+            connections,
+            outputs,
+            solos,
+        })
+    }
+
+    /// 用注册表重建图流，`num_threads` 与保存时的机器无关，由调用者决定
+    pub fn build(&self, registry: &Registry, num_threads: usize) -> Result<GraphFlow, ProjectError> {
+        let mut gf = GraphFlowBuilder {
+            sample_rate: self.sample_rate,
+            buffer_size: self.buffer_size,
+            num_channels: self.num_channels,
+            num_threads,
+            control_interval: self.control_interval,
+        }.build();
+
+        for entry in &self.blocks {
+            if gf.get_block(&entry.id).is_ok() {
+                return Err(ProjectError::DuplicateBlock(entry.id));
+            }
+            let block = registry.create_from(BlockType { name: entry.block_type.clone(), config: entry.config.clone() })?;
+            gf.insert_block(entry.id, block)?;
+            for (name, value) in &entry.params {
+                gf.param(&entry.id, name)?.set(*value);
+            }
+            for (name, lane) in &entry.automation {
+                let param = gf.param(&entry.id, name)?;
+                param.automation().set_lane(Lane::from(lane.nodes.clone()));
+                param.automation().set_enabled(lane.enabled);
+            }
+            gf.set_mode(&entry.id, entry.mode)?;
+        }
+        let port = |endpoint: &Endpoint| PortRef::Named(endpoint.block, endpoint.port.clone());
+        for connection in &self.connections {
+            match connection.kind {
+                EdgeKind::Normal => gf.connect(port(&connection.from), port(&connection.to))?,
+                EdgeKind::Feedback => gf.connect_feedback(port(&connection.from), port(&connection.to))?,
+            }
+        }
+        for output in &self.outputs {
+            gf.to_output(port(output))?;
+        }
+        for solo in &self.solos {
+            gf.set_solo(port(solo), true)?;
+        }
+        // 加载的工程不能撤销到空图
+        gf.clear_history();
+        // 参数直接到达保存时的值，不从默认值平滑过去
+        gf.reset();
+        Ok(gf)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Project is always serializable")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ProjectError> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+impl GraphFlow {
+    /// 把图流保存为 JSON 工程文件
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ProjectError> {
+        std::fs::write(path, Project::capture(self)?.to_json())?;
+        Ok(())
+    }
+
+    /// 从 JSON 工程文件加载图流，工作线程数取 `GraphFlowBuilder` 的默认值
+    pub fn load(path: impl AsRef<Path>, registry: &Registry) -> Result<GraphFlow, ProjectError> {
+        let project = Project::from_json(&std::fs::read_to_string(path)?)?;
+        project.build(registry, GraphFlowBuilder::default().num_threads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{IOData, PortLayout, Processor, SignalKind, Time};
+    use crate::musiblock::CurveType;
+    use crate::param::{Param, ParamHandle, ParamSpec};
+
+    fn node(t: f32, v: f32) -> Node {
+        Node { t, v, curve: CurveType::Linear, if_hold: false }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ConstConfig {
+        value: f32,
+    }
+
+    /// 输出常量乘以增益
+    struct Const {
+        value: f32,
+        gain: Param,
+    }
+
+    impl Processor for Const {
+        fn reset(&mut self) {
+            self.gain.reset();
+        }
+
+        fn ports(&self) -> PortLayout {
+            PortLayout::new().output("out", SignalKind::Audio)
+        }
+
+        fn params(&self) -> Vec<ParamHandle> {
+            vec![self.gain.handle()]
+        }
+
+        fn process(&mut self, _time: Time, _inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+            for sample in outputs[0].iter_mut() {
+                *sample = self.value * self.gain.tick();
+            }
+        }
+    }
+
+    fn registry() -> Registry {
+        let mut registry = Registry::new();
+        registry.register("const", |config: ConstConfig| Const {
+            value: config.value,
+            gain: Param::new(ParamSpec::new("gain", 0.0, 4.0, 1.0)),
+        });
+        registry.register("pass", |_: ()| |_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
+            outputs[0].copy_from_slice(&inputs[0]);
+        });
+        registry
+    }
+
+    #[test]
+    fn save_and_load() {
+        let registry = registry();
+        let mut gf = GraphFlowBuilder { buffer_size: 8, num_threads: 0, ..Default::default() }.build();
+        let source = gf.add_block(registry.create("const", ConstConfig { value: 0.5 }).unwrap()).unwrap();
+        let pass = gf.add_block(registry.create("pass", ()).unwrap()).unwrap();
+        let echo = gf.add_block(registry.create("pass", ()).unwrap()).unwrap();
+        gf.connect(source.named("out"), pass.port(0)).unwrap();
+        gf.connect_feedback(pass.port(0), echo.port(0)).unwrap();
+        gf.to_output(pass.port(0)).unwrap();
+        gf.to_output(echo.port(0)).unwrap();
+        gf.param(&source, "gain").unwrap().set(3.0);
+        gf.reset();
+
+        let json = Project::capture(&gf).unwrap().to_json();
+        let mut loaded = Project::from_json(&json).unwrap().build(&registry, 0).unwrap();
+        assert_eq!(Project::capture(&loaded).unwrap().to_json(), json);
+        assert_eq!(loaded.param(&source, "gain").unwrap().get(), 3.0);
+
+        let (mut expected, mut output) = (vec![0.0; 8], vec![0.0; 8]);
+        for _ in 0..2 {
+            gf.run(8, &mut expected);
+            loaded.run(8, &mut output);
+            assert_eq!(output, expected);
+        }
+        assert!(output.iter().all(|&sample| sample == 3.0));
+
+        // 不是由注册表创建的块无法保存，未注册的类型无法加载
+        gf.add_block(|_time: Time, _outputs: &mut IOData, _num_channels: usize| {}).unwrap();
+        assert!(matches!(Project::capture(&gf), Err(ProjectError::Unregistered(_))));
+        assert!(matches!(
+            Project::from_json(&json).unwrap().build(&Registry::new(), 0),
+            Err(ProjectError::UnknownType(_)),
+        ));
+    }
+
+    #[test]
+    fn save_automation_modes_and_solos() {
+        let registry = registry();
+        let mut gf = GraphFlowBuilder { sample_rate: 10, buffer_size: 4, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let source = gf.add_block(registry.create("const", ConstConfig { value: 0.5 }).unwrap()).unwrap();
+        let muted = gf.add_block(registry.create("pass", ()).unwrap()).unwrap();
+        let bypassed = gf.add_block(registry.create("pass", ()).unwrap()).unwrap();
+        gf.connect(source.named("out"), muted.port(0)).unwrap();
+        gf.connect(source.named("out"), bypassed.port(0)).unwrap();
+        gf.to_output(muted.port(0)).unwrap();
+        gf.to_output(bypassed.port(0)).unwrap();
+        gf.to_output(source.named("out")).unwrap();
+        gf.set_mode(&muted, BlockMode::Mute).unwrap();
+        gf.set_mode(&bypassed, BlockMode::Bypass).unwrap();
+        gf.set_solo(muted.port(0), true).unwrap();
+        gf.set_solo(bypassed.port(0), true).unwrap();
+        let gain = gf.param(&source, "gain").unwrap();
+        gain.automation().set_lane(Lane::from(vec![node(0.0, 1.0), node(0.8, 3.0)]));
+        gain.automation().set_enabled(true);
+        gf.reset();
+
+        let path = std::env::temp_dir().join(format!("project-{}.json", std::process::id()));
+        gf.save(&path).unwrap();
+        let mut loaded = GraphFlow::load(&path, &registry).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let project = Project::capture(&gf).unwrap();
+        assert_eq!(Project::capture(&loaded).unwrap(), project);
+        assert_eq!(project.blocks[0].automation["gain"].nodes.len(), 2);
+        assert_eq!((project.blocks[1].mode, project.blocks[2].mode), (BlockMode::Mute, BlockMode::Bypass));
+        assert_eq!(project.solos.len(), 2);
+
+        // 只有独奏的两路混入，其中静音的一路没有声音，旁通的一路跟随自动化的增益
+        let (mut expected, mut output) = (vec![0.0; 4], vec![0.0; 4]);
+        for _ in 0..2 {
+            gf.run(4, &mut expected);
+            loaded.run(4, &mut output);
+            assert_eq!(output, expected);
+        }
+        assert!(crate::approx_eq(output[3], 1.375));
+    }
+}
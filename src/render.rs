@@ -0,0 +1,171 @@
+//! 离线渲染：不经过声卡，直接循环调用 `GraphFlow::run` 并导出 WAV
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::graph_flow::GraphFlow;
+
+/// 渲染长度
+#[derive(Debug, Clone, Copy)]
+pub enum RenderLength {
+    /// 固定时长（秒）
+    Secs(f32),
+    /// 直到输出连续 `hold` 秒都不超过 `threshold`，最长 `max` 秒。末尾的静音会被裁掉
+    UntilSilent { threshold: f32, hold: f32, max: f32 },
+}
+
+/// 渲染结果，`samples` 为交错排列的多声道数据
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rendered {
+    pub sample_rate: u32,
+    pub num_channels: usize,
+    pub samples: Vec<f32>,
+}
+
+impl Rendered {
+    pub fn num_frames(&self) -> usize {
+        self.samples.len() / self.num_channels
+    }
+
+    pub fn as_secs_f32(&self) -> f32 {
+        self.num_frames() as f32 / self.sample_rate as f32
+    }
+
+    /// 写出 32 位浮点 WAV
+    pub fn write_wav<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        const FORMAT_IEEE_FLOAT: u16 = 3;
+        const BYTES_PER_SAMPLE: u32 = 4;
+
+        let num_channels = self.num_channels as u16;
+        let block_align = num_channels as u32 * BYTES_PER_SAMPLE;
+        let data_len = self.samples.len() as u32 * BYTES_PER_SAMPLE;
+        // fmt(18) + fact(4) + data 三个 chunk 及各自的 8 字节头
+        let riff_len = 4 + (8 + 18) + (8 + 4) + (8 + data_len);
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&riff_len.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&18u32.to_le_bytes())?;
+        writer.write_all(&FORMAT_IEEE_FLOAT.to_le_bytes())?;
+        writer.write_all(&num_channels.to_le_bytes())?;
+        writer.write_all(&self.sample_rate.to_le_bytes())?;
+        writer.write_all(&(self.sample_rate * block_align).to_le_bytes())?;
+        writer.write_all(&(block_align as u16).to_le_bytes())?;
+        writer.write_all(&(BYTES_PER_SAMPLE as u16 * 8).to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;  // cbSize
+
+        writer.write_all(b"fact")?;
+        writer.write_all(&4u32.to_le_bytes())?;
+        writer.write_all(&(self.num_frames() as u32).to_le_bytes())?;
+
+        writer.write_all(b"data")?;
+        writer.write_all(&data_len.to_le_bytes())?;
+        for sample in self.samples.iter() {
+            writer.write_all(&sample.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    pub fn to_wav_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_wav(&mut bytes).expect("writing to a Vec cannot fail");
+        bytes
+    }
+
+    pub fn save_wav(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_wav(&mut writer)
+    }
+}
+
+/// 以图流当前的 `buffer_size` 为单位，尽可能快地运行图流
+///
+/// 渲染前先 `reset`，总是从时间零点开始。图流已经拆分（`GraphFlow::split`）时 panic，
+/// 运行部分在引擎手里。
+pub fn render(gf: &mut GraphFlow, length: RenderLength) -> Rendered {
+    assert!(!gf.is_split(), "GraphFlow has been split, cannot render offline");
+    gf.reset();
+    let sample_rate = gf.sample_rate();
+    let num_channels = gf.num_channels();
+    let buffer_size = gf.buffer_size();
+    let secs_to_samples = |secs: f32| (secs * sample_rate as f32) as usize * num_channels;
+
+    let (max_samples, silence) = match length {
+        RenderLength::Secs(secs) => (secs_to_samples(secs), None),
+        RenderLength::UntilSilent { threshold, hold, max } => {
+            (secs_to_samples(max), Some((threshold, secs_to_samples(hold))))
+        }
+    };
+
+    let mut samples = Vec::with_capacity(max_samples);
+    let mut buffer = vec![0.0; buffer_size as usize];
+    // 最后一个超过阈值的样本之后的位置
+    let mut sound_end = 0;
+
+    while samples.len() < max_samples {
+        gf.run(buffer_size, &mut buffer);
+        let start = samples.len();
+        samples.extend_from_slice(&buffer);
+
+        if let Some((threshold, hold)) = silence {
+            if let Some(last) = buffer.iter().rposition(|sample| sample.abs() > threshold) {
+                sound_end = start + last + 1;
+            }
+            if samples.len() - sound_end >= hold {
+                // 对齐到帧
+                samples.truncate(sound_end.div_ceil(num_channels) * num_channels);
+                break;
+            }
+        }
+    }
+    samples.truncate(max_samples);
+
+    Rendered {
+        sample_rate,
+        num_channels,
+        samples,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{IOData, Time};
+    use crate::graph_flow::GraphFlowBuilder;
+
+    // 0.1 秒的直流，之后静音
+    fn burst() -> GraphFlow {
+        let mut gf = GraphFlowBuilder::default().build();
+        let burst = gf.add_block(|time: Time, outputs: &mut IOData, _num_channels: usize| {
+            let level = if time.as_secs_f32() < 0.1 { 0.5 } else { 0.0 };
+            outputs[0].fill(level);
+        }).unwrap();
+        gf.to_output(burst.port(0)).unwrap();
+        gf
+    }
+
+    #[test]
+    fn render_to_wav() {
+        let mut gf = burst();
+        let rendered = render(&mut gf, RenderLength::Secs(0.25));
+        assert_eq!(rendered.num_frames(), 12000);
+        assert_eq!(rendered.samples.len(), 24000);
+        // 再次渲染从头开始
+        assert_eq!(render(&mut gf, RenderLength::Secs(0.25)), rendered);
+
+        let silent = render(&mut burst(), RenderLength::UntilSilent { threshold: 1e-4, hold: 0.05, max: 10.0 });
+        // 按 256 帧的缓冲区判断，0.1 秒落在第 19 个缓冲区内
+        assert_eq!(silent.num_frames(), 19 * 256);
+        assert!(silent.samples.iter().all(|&sample| sample == 0.5));
+
+        let bytes = silent.to_wav_bytes();
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[8..12], b"WAVE");
+        assert_eq!(bytes.len(), 58 + silent.samples.len() * 4);
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+        assert_eq!(f32::from_le_bytes(bytes[58..62].try_into().unwrap()), 0.5);
+    }
+}
@@ -0,0 +1,1122 @@
+//! 图流的运行计划与调度
+//!
+//! 每次结构变化（加删块、连线、输出）时，`GraphFlow` 把当前的拓扑序编译成一份 `Plan`：
+//! 所有端口缓冲区在编译时按最大缓冲区预先分配好，运行时只在预留容量内改变长度。
+//! 运行时每个节点有一个原子的剩余入度，线程池里的线程（以及音频线程自己）
+//! 扫描入度为 0 的节点并用 CAS 认领，处理完后递减下游的入度，整个过程不加锁也不分配。
+//!
+//! 编译时还会按各块报告的延迟计算每条路径到达的时间，在较早到达的连接（以及图流输出）上
+//! 插入补偿延迟，使汇入同一个节点的所有路径对齐。
+
+use std::cell::UnsafeCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::block::{Block, BlockId, IOData, Port, PortLayout, PortSpec, ProcessorCell, SignalKind, Time};
+use crate::channel::{mix_channels, mix_frame, ChannelLayout, MAX_FRAME_CHANNELS};
+use crate::event::{Event, EventBuffer, EVENT_CAPACITY};
+use crate::param::ParamHandle;
+use crate::profile::{GraphStats, Stats};
+use crate::switch::{fade_step, BlockMode, Fade, ModeHandle, SoloHandle, SWITCH_FADE_SECS};
+use crate::tap::Taps;
+
+/// 节点已被认领（或上一个缓冲区已处理完）
+const CLAIMED: usize = usize::MAX;
+
+struct NodeBuffers {
+    inputs: IOData,
+    outputs: IOData,
+    /// 上一个缓冲区的反馈输入，只有存在反馈连接时才分配
+    feedback: Option<IOData>,
+    /// 每条控制到音频连接上一个缓冲区的最后一个控制值，与 `inputs`、`feedback_inputs` 一一对应
+    input_last: Vec<f32>,
+    feedback_last: Vec<f32>,
+    /// 延迟补偿，与 `inputs`、`graph_inputs` 一一对应
+    input_delays: Vec<Option<DelayLine>>,
+    graph_input_delays: Vec<Option<DelayLine>>,
+    /// 每条连接当前的增益，与 `inputs`、`feedback_inputs` 一一对应
+    input_gains: Vec<Fade>,
+    feedback_gains: Vec<Fade>,
+    /// 处理结果与旁通信号当前的增益
+    active_gain: Fade,
+    bypass_gain: Fade,
+}
+
+struct PlanNode {
+    block_id: BlockId,
+    processor: Arc<ProcessorCell>,
+    mode: ModeHandle,
+    stats: Arc<Stats>,
+    ports: PortLayout,
+    /// 块自己的延迟（帧）
+    latency: usize,
+    /// 已被删除、正在淡出的块，见 `Base::Running`
+    ghost: bool,
+    /// (上游节点下标, 上游端口, 本节点端口)
+    inputs: Vec<(usize, usize, usize)>,
+    feedback_inputs: Vec<(usize, usize, usize)>,
+    /// 每条连接的目标增益：0 表示已经断开、正在淡出
+    input_targets: Vec<f32>,
+    feedback_targets: Vec<f32>,
+    /// (图流输入, 本节点端口)，图流作为子图运行时由外层写入
+    graph_inputs: Vec<(usize, usize)>,
+    /// 有普通输入的端口，每个缓冲区开始前清零
+    input_ports: Vec<usize>,
+    /// 有反馈输入的端口
+    feedback_ports: Vec<usize>,
+    /// 被下游或图流输出读取的端口，处理前清零
+    used_outputs: Vec<usize>,
+    /// 普通连接的下游，每条连接一项
+    successors: Vec<usize>,
+    d_in: usize,
+    pending: AtomicUsize,
+    buffers: UnsafeCell<NodeBuffers>,
+}
+
+/// 编译时决定缓冲区大小的参数
+#[derive(Clone, Copy)]
+pub(crate) struct Format {
+    pub(crate) max_buffer_size: usize,
+    pub(crate) num_channels: usize,
+    pub(crate) control_interval: usize,
+}
+
+/// 编译新计划时参照的旧计划
+#[derive(Clone, Copy)]
+pub(crate) enum Base<'a> {
+    /// 旧计划不在运行，新计划直接沿用它的输出
+    Idle(&'a Plan),
+    /// 旧计划正在引擎中运行，只读取它的结构，换上时由 `Plan::take_over` 交接状态
+    ///
+    /// 块在两份计划之间共享，每个缓冲区只能运行一次，所以交叉淡化放在新计划里完成：
+    /// 新的连接与输出从 0 淡入，断开的连接、移出的输出与删除的块保留下来淡出。
+    Running(&'a Plan, Time),
+}
+
+/// 编译中的节点，连接还以块表示
+struct Draft<'a> {
+    block_id: BlockId,
+    processor: &'a Arc<ProcessorCell>,
+    mode: &'a ModeHandle,
+    stats: &'a Arc<Stats>,
+    ports: &'a PortLayout,
+    latency: usize,
+    ghost: bool,
+    /// (上游块, 上游端口, 本节点端口, 目标增益)
+    inputs: Vec<(BlockId, usize, usize, f32)>,
+    feedback_inputs: Vec<(BlockId, usize, usize, f32)>,
+    graph_inputs: Vec<(usize, usize)>,
+}
+
+impl Draft<'_> {
+    /// 运行顺序：尽量保持 `drafts` 原来的顺序（新的拓扑序在前，删除的块在后）
+    ///
+    /// 淡出的连接可能与新的连接成环，这时它们全部改为延迟一个缓冲区读取，与反馈连接相同。
+    fn order(drafts: &mut [Draft]) -> Vec<usize> {
+        let sort = |drafts: &[Draft]| {
+            let mut d_in = drafts.iter().map(|draft| draft.inputs.len()).collect::<Vec<_>>();
+            let mut order = Vec::with_capacity(drafts.len());
+            while let Some(i) = (0..drafts.len()).find(|i| d_in[*i] == 0) {
+                d_in[i] = usize::MAX;
+                order.push(i);
+                for (j, draft) in drafts.iter().enumerate() {
+                    let links = draft.inputs.iter().filter(|(pre_block_id, ..)| *pre_block_id == drafts[i].block_id).count();
+                    d_in[j] -= links;
+                }
+            }
+            order
+        };
+        let order = sort(drafts);
+        if order.len() == drafts.len() {
+            return order;
+        }
+        for draft in drafts.iter_mut() {
+            let ghost = draft.ghost;
+            let (inputs, fading) = draft.inputs.drain(..).partition(|(.., target)| !ghost && *target == 1.0);
+            draft.inputs = inputs;
+            draft.feedback_inputs.extend::<Vec<_>>(fading);
+        }
+        (0..drafts.len()).collect()
+    }
+}
+
+pub(crate) struct Plan {
+    nodes: Vec<PlanNode>,
+    index: HashMap<BlockId, usize>,
+    /// (节点下标, 端口)
+    outputs: Vec<(usize, usize)>,
+    /// 与 `outputs` 一一对应，已被移出、正在淡出的输出
+    ghost_outputs: Vec<bool>,
+    /// 与 `outputs` 一一对应的延迟补偿，只在两次运行之间使用
+    output_delays: UnsafeCell<Vec<Option<DelayLine>>>,
+    /// 与 `outputs` 一一对应的独奏开关与当前增益
+    output_solos: Vec<SoloHandle>,
+    output_gains: UnsafeCell<Vec<Fade>>,
+    /// 每个节点的输出相对于图流源头的延迟（帧），以及图流输出的总延迟
+    latencies: Vec<usize>,
+    latency: usize,
+    /// 所有块的参数，每个缓冲区开始时对自动化求值
+    block_params: Vec<ParamHandle>,
+    /// 图流输入的数据，只在两次运行之间写入
+    graph_inputs: UnsafeCell<IOData>,
+    /// 本次运行的时间与缓冲区长度，只在没有节点可认领时写入
+    params: UnsafeCell<(Time, usize)>,
+    num_channels: usize,
+    remaining: AtomicUsize,
+    /// 已经运行的帧数，到 `fade_frames` 为止；编辑线程据此判断淡化是否结束
+    played: AtomicUsize,
+    fade_frames: usize,
+}
+
+// 缓冲区与参数的独占访问由 `pending` 的认领顺序保证，见 `Plan::run`
+unsafe impl Sync for Plan {}
+
+impl Plan {
+    pub(crate) fn empty(time: Time, num_channels: usize) -> Self {
+        Plan {
+            nodes: Vec::new(),
+            index: HashMap::new(),
+            outputs: Vec::new(),
+            ghost_outputs: Vec::new(),
+            output_delays: UnsafeCell::new(Vec::new()),
+            output_solos: Vec::new(),
+            output_gains: UnsafeCell::new(Vec::new()),
+            latencies: Vec::new(),
+            latency: 0,
+            block_params: Vec::new(),
+            graph_inputs: UnsafeCell::new(IOData::new(0, 0)),
+            params: UnsafeCell::new((time, 0)),
+            num_channels,
+            remaining: AtomicUsize::new(0),
+            played: AtomicUsize::new(0),
+            fade_frames: fade_frames(time),
+        }
+    }
+
+    /// 按拓扑序编译运行计划
+    ///
+    /// 旧计划不在运行时沿用它的输出（反馈连接因此不会在编辑时被清空），否则见 `Base::Running`。
+    pub(crate) fn compile(
+        topo: &[BlockId],
+        blocks: &HashMap<BlockId, Block>,
+        outputs: &HashMap<Port, SoloHandle>,
+        graph_inputs: &[PortSpec],
+        graph_input_edges: &HashSet<(usize, Port)>,
+        format: Format,
+        base: Base,
+    ) -> Self {
+        let Format { max_buffer_size, num_channels, control_interval } = format;
+        let (old, (time, buffer_size), running) = match base {
+            Base::Idle(old) => (old, old.params(), false),
+            Base::Running(old, time) => (old, (time, 0), true),
+        };
+
+        let links = |inputs: &HashMap<BlockId, HashSet<(usize, usize)>>| inputs.iter()
+            .flat_map(|(pre_block_id, pre_ports)| {
+                pre_ports.iter().map(|(from_port, to_port)| (*pre_block_id, *from_port, *to_port, 1.0))
+            })
+            .collect::<Vec<_>>();
+        let mut drafts = topo.iter().map(|block_id| {
+            let block = &blocks[block_id];
+            let mut graph_inputs = graph_input_edges.iter()
+                .filter(|(_, to)| to.block_id == *block_id)
+                .map(|(input, to)| (*input, to.port))
+                .collect::<Vec<_>>();
+            graph_inputs.sort_unstable();
+            Draft {
+                block_id: *block_id,
+                processor: &block.processor,
+                mode: &block.mode,
+                stats: &block.stats,
+                ports: &block.ports,
+                latency: block.latency,
+                ghost: false,
+                inputs: links(&block.inputs),
+                feedback_inputs: links(&block.feedback_inputs),
+                graph_inputs,
+            }
+        }).collect::<Vec<_>>();
+        if running {
+            old.add_ghosts(&mut drafts);
+        }
+        let order = Draft::order(&mut drafts);
+        let index = order.iter().enumerate().map(|(i, j)| (drafts[*j].block_id, i)).collect::<HashMap<_, _>>();
+        let resolve = |links: &[(BlockId, usize, usize, f32)]| {
+            let mut resolved = links.iter()
+                .map(|(pre_block_id, from_port, to_port, target)| (index[pre_block_id], *from_port, *to_port, *target))
+                .collect::<Vec<_>>();
+            resolved.sort_unstable_by_key(|(pre, from_port, to_port, _)| (*pre, *from_port, *to_port));
+            resolved.into_iter()
+                .map(|(pre, from_port, to_port, target)| ((pre, from_port, to_port), target))
+                .unzip::<_, _, Vec<_>, Vec<_>>()
+        };
+        // 换上新计划时新的连接从 0 淡入、断开的连接从 1 淡出，沿用的连接由 `Plan::take_over` 接过增益；
+        // 新块的输出本身会淡入，它的输入不再淡化
+        let initial_gains = |targets: &[f32], fresh: bool| targets.iter()
+            .map(|target| Fade(if running && !fresh { 1.0 - target } else { 1.0 }))
+            .collect::<Vec<_>>();
+
+        let mut nodes = order.iter().map(|j| {
+            let draft = &drafts[*j];
+            let (inputs, input_targets) = resolve(&draft.inputs);
+            let (feedback_inputs, feedback_targets) = resolve(&draft.feedback_inputs);
+            let distinct_ports = |inputs: &[(usize, usize, usize)]| {
+                let mut ports = inputs.iter().map(|(_, _, to_port)| *to_port).collect::<Vec<_>>();
+                ports.sort_unstable();
+                ports.dedup();
+                ports
+            };
+            let mut input_ports = distinct_ports(&inputs);
+            input_ports.extend(draft.graph_inputs.iter().map(|(_, to_port)| *to_port));
+            input_ports.sort_unstable();
+            input_ports.dedup();
+            let feedback_ports = distinct_ports(&feedback_inputs);
+
+            let with_capacity = |specs| IOData::with_capacity(specs, buffer_size, max_buffer_size, num_channels, control_interval);
+            let mut outputs = with_capacity(&draft.ports.outputs);
+            if let Some(old_buffers) = old.index.get(&draft.block_id).filter(|_| !running).map(|i| old.buffers(*i)) {
+                if old_buffers.outputs.port_len() == draft.ports.outputs.len() {
+                    for port in 0..draft.ports.outputs.len() {
+                        outputs[port].clear();
+                        outputs[port].extend_from_slice(&old_buffers.outputs[port]);
+                    }
+                    outputs.set_buffer_size(buffer_size, num_channels);
+                }
+            }
+            let feedback = (!feedback_inputs.is_empty()).then(|| with_capacity(&draft.ports.inputs));
+            // 编译时直接处于当前模式，不重新淡化
+            let (active_gain, bypass_gain) = draft.mode.get().gains();
+            let input_last = vec![f32::NAN; inputs.len()];
+            let feedback_last = vec![f32::NAN; feedback_inputs.len()];
+            let fresh = !old.index.contains_key(&draft.block_id);
+            let input_gains = initial_gains(&input_targets, fresh);
+            let feedback_gains = initial_gains(&feedback_targets, fresh);
+
+            PlanNode {
+                block_id: draft.block_id,
+                processor: Arc::clone(draft.processor),
+                mode: draft.mode.clone(),
+                stats: Arc::clone(draft.stats),
+                ports: draft.ports.clone(),
+                latency: draft.latency,
+                ghost: draft.ghost,
+                d_in: inputs.len(),
+                inputs,
+                feedback_inputs,
+                input_targets,
+                feedback_targets,
+                graph_inputs: draft.graph_inputs.clone(),
+                input_ports,
+                feedback_ports,
+                used_outputs: Vec::new(),
+                successors: Vec::new(),
+                pending: AtomicUsize::new(CLAIMED),
+                buffers: UnsafeCell::new(NodeBuffers {
+                    inputs: with_capacity(&draft.ports.inputs),
+                    outputs,
+                    feedback,
+                    input_last,
+                    feedback_last,
+                    input_delays: Vec::new(),
+                    graph_input_delays: Vec::new(),
+                    input_gains,
+                    feedback_gains,
+                    active_gain: Fade(active_gain),
+                    bypass_gain: Fade(bypass_gain),
+                }),
+            }
+        }).collect::<Vec<_>>();
+
+        // 反向填充下游与被读取的输出端口
+        for i in 0..nodes.len() {
+            for (pre, from_port, _) in nodes[i].inputs.clone() {
+                nodes[pre].successors.push(i);
+                nodes[pre].used_outputs.push(from_port);
+            }
+            for (pre, from_port, _) in nodes[i].feedback_inputs.clone() {
+                nodes[pre].used_outputs.push(from_port);
+            }
+        }
+        let mut plan_outputs = outputs.iter()
+            .map(|(port, solo)| (index[&port.block_id], port.port, false, solo.clone()))
+            .collect::<Vec<_>>();
+        if running {
+            // 被移出的输出淡出，旧计划还没淡化完时其中正在淡出的输出继续淡出
+            let fading = !old.settled();
+            let old_outputs = old.outputs.iter().zip(old.ghost_outputs.iter()).zip(old.output_solos.iter());
+            for (((i, port), ghost), solo) in old_outputs {
+                let block_id = old.nodes[*i].block_id;
+                if let (true, false, Some(i)) = (!*ghost || fading, outputs.contains_key(&block_id.port(*port)), index.get(&block_id)) {
+                    plan_outputs.push((*i, *port, true, solo.clone()));
+                }
+            }
+        }
+        plan_outputs.sort_unstable_by_key(|(i, port, _, _)| (*i, *port));
+        let ghost_outputs = plan_outputs.iter().map(|(_, _, ghost, _)| *ghost).collect::<Vec<_>>();
+        let output_solos = plan_outputs.iter().map(|(_, _, _, solo)| solo.clone()).collect::<Vec<_>>();
+        let plan_outputs = plan_outputs.into_iter().map(|(i, port, _, _)| (i, port)).collect::<Vec<_>>();
+        let output_gains = solo_targets(&output_solos, &ghost_outputs).zip(ghost_outputs.iter())
+            .map(|(target, ghost)| Fade(if running { if *ghost { 1.0 } else { 0.0 } } else { target }))
+            .collect();
+        for (i, port) in plan_outputs.iter() {
+            nodes[*i].used_outputs.push(*port);
+        }
+        for node in nodes.iter_mut() {
+            node.used_outputs.sort_unstable();
+            node.used_outputs.dedup();
+        }
+
+        // 延迟补偿：节点的输入在最晚的一条路径到达时对齐，拓扑序保证上游先算完；淡出的连接不参与对齐
+        let new_delay = |frames: usize, spec: &PortSpec| {
+            (frames > 0).then(|| DelayLine::new(frames, spec, buffer_size, format))
+        };
+        let mut latencies = vec![0; nodes.len()];
+        for i in 0..nodes.len() {
+            let arrival = nodes[i].inputs.iter().zip(nodes[i].input_targets.iter())
+                .filter(|(_, target)| **target == 1.0)
+                .map(|((pre, _, _), _)| latencies[*pre])
+                .max()
+                .unwrap_or(0);
+            latencies[i] = arrival + nodes[i].latency;
+
+            let input_delays = nodes[i].inputs.iter().map(|(pre, from_port, _)| {
+                new_delay(arrival.saturating_sub(latencies[*pre]), &nodes[*pre].ports.outputs[*from_port])
+            }).collect();
+            let graph_input_delays = nodes[i].graph_inputs.iter()
+                .map(|(input, _)| new_delay(arrival, &graph_inputs[*input]))
+                .collect();
+            let buffers = nodes[i].buffers.get_mut();
+            buffers.input_delays = input_delays;
+            buffers.graph_input_delays = graph_input_delays;
+        }
+        let latency = plan_outputs.iter().zip(ghost_outputs.iter())
+            .filter(|(_, ghost)| !**ghost)
+            .map(|((i, _), _)| latencies[*i])
+            .max()
+            .unwrap_or(0);
+        let output_delays = plan_outputs.iter()
+            .map(|(i, port)| new_delay(latency.saturating_sub(latencies[*i]), &nodes[*i].ports.outputs[*port]))
+            .collect();
+
+        Plan {
+            nodes,
+            index,
+            outputs: plan_outputs,
+            ghost_outputs,
+            output_delays: UnsafeCell::new(output_delays),
+            output_solos,
+            output_gains: UnsafeCell::new(output_gains),
+            latencies,
+            latency,
+            block_params: topo.iter().flat_map(|block_id| blocks[block_id].params.iter().cloned()).collect(),
+            graph_inputs: UnsafeCell::new(
+                IOData::with_capacity(graph_inputs, buffer_size, max_buffer_size, num_channels, control_interval),
+            ),
+            params: UnsafeCell::new((time, buffer_size)),
+            num_channels,
+            remaining: AtomicUsize::new(0),
+            played: AtomicUsize::new(0),
+            fade_frames: fade_frames(time),
+        }
+    }
+
+    /// 把本计划中已被删除的块与已经断开的连接加入 `drafts`，让它们在新计划中淡出
+    ///
+    /// 删除的块照常接收输入，由静音模式淡出；保留的块上断开的连接目标增益为 0。
+    /// 本计划还没淡化完时，其中正在淡出的块、连接与输出一起保留，换上后从当前增益继续淡出。
+    fn add_ghosts<'a>(&'a self, drafts: &mut Vec<Draft<'a>>) {
+        let fading = !self.settled();
+        let alive = |node: &PlanNode| !node.ghost || fading;
+        let retained = drafts.iter().map(|draft| draft.block_id).collect::<HashSet<_>>();
+        for node in self.nodes.iter().filter(|node| alive(node) && !retained.contains(&node.block_id)) {
+            drafts.push(Draft {
+                block_id: node.block_id,
+                processor: &node.processor,
+                mode: &node.mode,
+                stats: &node.stats,
+                ports: &node.ports,
+                latency: node.latency,
+                ghost: true,
+                inputs: Vec::new(),
+                feedback_inputs: Vec::new(),
+                graph_inputs: node.graph_inputs.clone(),
+            });
+        }
+        let present = drafts.iter().map(|draft| draft.block_id).collect::<HashSet<_>>();
+        let old_links = |inputs: &[(usize, usize, usize)], targets: &[f32]| inputs.iter().zip(targets.iter())
+            .filter(|(_, target)| **target == 1.0 || fading)
+            .map(|((pre, from_port, to_port), target)| (self.nodes[*pre].block_id, *from_port, *to_port, *target))
+            .filter(|(pre_block_id, ..)| present.contains(pre_block_id))
+            .collect::<Vec<_>>();
+
+        for draft in drafts.iter_mut() {
+            let Some(node) = self.index.get(&draft.block_id).map(|i| &self.nodes[*i]).filter(|node| alive(node)) else {
+                continue;
+            };
+            let connected = |link: &(BlockId, usize, usize)| draft.inputs.iter().chain(draft.feedback_inputs.iter())
+                .any(|(pre_block_id, from_port, to_port, _)| (*pre_block_id, *from_port, *to_port) == *link);
+            // 删除的块保留原来的连接，保留的块上断开的连接淡出
+            let ghost = draft.ghost;
+            let removed = |links: Vec<(BlockId, usize, usize, f32)>| links.into_iter()
+                .filter(|(pre_block_id, from_port, to_port, _)| !connected(&(*pre_block_id, *from_port, *to_port)))
+                .map(|(pre_block_id, from_port, to_port, target)| (pre_block_id, from_port, to_port, if ghost { target } else { 0.0 }))
+                .collect::<Vec<_>>();
+            let inputs = removed(old_links(&node.inputs, &node.input_targets));
+            let feedback_inputs = removed(old_links(&node.feedback_inputs, &node.feedback_targets));
+            draft.inputs.extend(inputs);
+            draft.feedback_inputs.extend(feedback_inputs);
+        }
+    }
+
+    /// 从正在运行的旧计划接过共享块的状态：输出、模式淡化、连接与输出的增益
+    ///
+    /// 引擎在换上本计划前调用，两份计划都不在运行。
+    pub(crate) fn take_over(&self, old: &Plan) {
+        unsafe { *self.params.get() = old.params() };
+        let same_link = |link: &(usize, usize, usize), old_link: &(usize, usize, usize)| {
+            self.nodes[link.0].block_id == old.nodes[old_link.0].block_id && (link.1, link.2) == (old_link.1, old_link.2)
+        };
+        for node in self.nodes.iter() {
+            let Some(j) = old.index.get(&node.block_id) else {
+                continue;
+            };
+            let (old_node, old_buffers) = (&old.nodes[*j], old.buffers(*j));
+            let buffers = unsafe { &mut *node.buffers.get() };
+            if buffers.outputs.port_len() == old_buffers.outputs.port_len() {
+                buffers.outputs.set_buffer_size(old_buffers.outputs.buffer_size(), self.num_channels);
+                for port in 0..buffers.outputs.port_len() {
+                    copy_port(&old_buffers.outputs, port, &mut buffers.outputs, port);
+                }
+            }
+            buffers.active_gain = old_buffers.active_gain;
+            buffers.bypass_gain = old_buffers.bypass_gain;
+            let links = node.inputs.iter().zip(buffers.input_gains.iter_mut()).zip(buffers.input_last.iter_mut());
+            for ((link, gain), last) in links {
+                if let Some(k) = old_node.inputs.iter().position(|old_link| same_link(link, old_link)) {
+                    (*gain, *last) = (old_buffers.input_gains[k], old_buffers.input_last[k]);
+                }
+            }
+            let links = node.feedback_inputs.iter().zip(buffers.feedback_gains.iter_mut()).zip(buffers.feedback_last.iter_mut());
+            for ((link, gain), last) in links {
+                if let Some(k) = old_node.feedback_inputs.iter().position(|old_link| same_link(link, old_link)) {
+                    (*gain, *last) = (old_buffers.feedback_gains[k], old_buffers.feedback_last[k]);
+                }
+            }
+        }
+
+        let (output_gains, old_gains) = unsafe { (&mut *self.output_gains.get(), &*old.output_gains.get()) };
+        for ((i, port), gain) in self.outputs.iter().zip(output_gains.iter_mut()) {
+            let same_output = |(j, old_port): &(usize, usize)| old.nodes[*j].block_id == self.nodes[*i].block_id && old_port == port;
+            if let Some(k) = old.outputs.iter().position(same_output) {
+                *gain = old_gains[k];
+            }
+        }
+    }
+
+    fn params(&self) -> (Time, usize) {
+        unsafe { *self.params.get() }
+    }
+
+    /// 换上后已经运行满一次淡化的时长，其中的淡入淡出都已结束
+    fn settled(&self) -> bool {
+        self.played.load(Ordering::Relaxed) >= self.fade_frames
+    }
+
+    // 只在两次运行之间调用
+    fn buffers(&self, i: usize) -> &NodeBuffers {
+        unsafe { &*self.nodes[i].buffers.get() }
+    }
+
+    /// 运行一个缓冲区，所有节点处理完后返回
+    ///
+    /// 上一次运行结束时所有节点都处于 `CLAIMED`，没有线程会访问缓冲区，
+    /// 所以这里可以先写入参数和反馈输入，再按拓扑逆序放开入度：
+    /// 节点可被认领时，它的下游都已经设置好了入度。
+    pub(crate) fn run(&self, time: Time, buffer_size: usize, pool: &ThreadPool) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        unsafe { *self.params.get() = (time, buffer_size) };
+        self.gather_feedback(buffer_size);
+
+        self.remaining.store(self.nodes.len(), Ordering::Relaxed);
+        for node in self.nodes.iter().rev() {
+            node.pending.store(node.d_in, Ordering::Release);
+        }
+        if pool.workers.is_empty() {
+            // 单线程：按拓扑序依次处理，上游总在下游之前完成
+            for (i, node) in self.nodes.iter().enumerate() {
+                let claimed = node.pending.compare_exchange(0, CLAIMED, Ordering::Acquire, Ordering::Relaxed);
+                debug_assert!(claimed.is_ok());
+                unsafe { self.execute(i) };
+            }
+            return;
+        }
+        pool.wake();
+
+        while self.remaining.load(Ordering::Acquire) > 0 {
+            if !self.work() {
+                std::hint::spin_loop();
+            }
+        }
+    }
+
+    /// 运行一个缓冲区：对自动化求值、处理、记录负载并写入探针
+    pub(crate) fn process(&self, time: Time, buffer_size: usize, pool: &ThreadPool, stats: &GraphStats, taps: &Taps) {
+        for param in self.block_params.iter() {
+            param.automation().begin_buffer(time.sample(), time.sample_rate());
+        }
+        let start = Instant::now();
+        self.run(time, buffer_size, pool);
+        let frames = buffer_size / self.num_channels;
+        stats.record(start.elapsed(), Duration::from_secs_f64(frames as f64 / time.sample_rate() as f64));
+        if !self.settled() {
+            self.played.fetch_add(frames, Ordering::Relaxed);
+        }
+
+        // 添加探针时锁被短暂占用，这个缓冲区就不写入
+        if let Ok(taps) = taps.try_lock() {
+            for (port, ring) in taps.iter() {
+                // 读取端已经丢弃，不在音频线程上释放
+                if Arc::strong_count(ring) > 1 {
+                    self.with_output(*port, |data, port| ring.push_port(data, port));
+                }
+            }
+        }
+    }
+
+    fn gather_feedback(&self, buffer_size: usize) {
+        let step = fade_step(self.params().0.sample_rate());
+        for node in self.nodes.iter() {
+            // 只借用 feedback 等字段，自环时还要读同一节点的 outputs 字段
+            let buffers = node.buffers.get();
+            let Some(feedback) = (unsafe { (*buffers).feedback.as_mut() }) else {
+                continue;
+            };
+            let (feedback_last, feedback_gains) = unsafe { (&mut (*buffers).feedback_last, &mut (*buffers).feedback_gains) };
+            feedback.set_buffer_size(buffer_size, self.num_channels);
+            for port in node.feedback_ports.iter() {
+                feedback[*port].fill(0.0);
+                feedback.events_mut(*port).clear();
+            }
+            let links = node.feedback_inputs.iter().zip(node.feedback_targets.iter())
+                .zip(feedback_last.iter_mut().zip(feedback_gains.iter_mut()));
+            for (((pre, from_port, to_port), target), (last, gain)) in links {
+                if gain.0 == 0.0 && *target == 0.0 {
+                    continue;
+                }
+                let pre_outputs = unsafe { &(*self.nodes[*pre].buffers.get()).outputs };
+                let fade = (gain.0 != *target).then_some((gain, *target, step));
+                mix_port(pre_outputs, *from_port, feedback, *to_port, last, fade);
+            }
+        }
+    }
+
+    /// 认领并处理一个入度为 0 的节点，没有可处理的节点时返回 false
+    fn work(&self) -> bool {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.pending.load(Ordering::Relaxed) == 0
+                && node.pending.compare_exchange(0, CLAIMED, Ordering::Acquire, Ordering::Relaxed).is_ok()
+            {
+                unsafe { self.execute(i) };
+                return true;
+            }
+        }
+        false
+    }
+
+    /// # Safety
+    ///
+    /// 节点 `i` 必须已被当前线程认领
+    unsafe fn execute(&self, i: usize) {
+        let node = &self.nodes[i];
+        let (time, buffer_size) = self.params();
+        let buffers = &mut *node.buffers.get();
+
+        if buffers.inputs.buffer_size() != buffer_size {
+            buffers.inputs.set_buffer_size(buffer_size, self.num_channels);
+        }
+        if buffers.outputs.buffer_size() != buffer_size {
+            buffers.outputs.set_buffer_size(buffer_size, self.num_channels);
+        }
+        for port in node.input_ports.iter() {
+            buffers.inputs[*port].fill(0.0);
+            buffers.inputs.events_mut(*port).clear();
+        }
+        if let Some(feedback) = buffers.feedback.as_ref() {
+            for port in node.feedback_ports.iter() {
+                buffers.inputs[*port].copy_from_slice(&feedback[*port]);
+                let events = buffers.inputs.events_mut(*port);
+                events.clear();
+                events.merge(feedback.events(*port));
+            }
+        }
+        // 上游都已处理完，只读
+        let step = fade_step(time.sample_rate());
+        let links = node.inputs.iter().zip(node.input_targets.iter())
+            .zip(buffers.input_last.iter_mut().zip(buffers.input_gains.iter_mut()))
+            .zip(buffers.input_delays.iter_mut());
+        for ((((pre, from_port, to_port), target), (last, gain)), delay) in links {
+            if gain.0 == 0.0 && *target == 0.0 {
+                continue;
+            }
+            let pre_outputs = &(*self.nodes[*pre].buffers.get()).outputs;
+            let (from, from_port) = delayed(pre_outputs, *from_port, delay);
+            let fade = (gain.0 != *target).then_some((gain, *target, step));
+            mix_port(from, from_port, &mut buffers.inputs, *to_port, last, fade);
+        }
+        // 两端种类相同，不需要保存转换状态
+        let graph_inputs = &*self.graph_inputs.get();
+        let mut last = f32::NAN;
+        for ((input, to_port), delay) in node.graph_inputs.iter().zip(buffers.graph_input_delays.iter_mut()) {
+            let (from, from_port) = delayed(graph_inputs, *input, delay);
+            mix_port(from, from_port, &mut buffers.inputs, *to_port, &mut last, None);
+        }
+        for port in node.used_outputs.iter() {
+            buffers.outputs[*port].fill(0.0);
+        }
+        buffers.outputs.clear_events();
+
+        // 旁通或静音且淡化已完成时不处理，删除的块按静音淡出
+        let mode = if node.ghost { BlockMode::Mute } else { node.mode.get() };
+        let (active, bypass) = mode.gains();
+        let settled = buffers.active_gain.0 == active && buffers.bypass_gain.0 == bypass;
+        if settled && active == 0.0 {
+            for port in 0..buffers.outputs.port_len() {
+                buffers.outputs[port].fill(0.0);
+            }
+        } else {
+            let start = Instant::now();
+            node.processor.get_mut().process(time, &buffers.inputs, &mut buffers.outputs, self.num_channels);
+            node.stats.record(start.elapsed());
+        }
+        if !(settled && active == 1.0) {
+            switch_outputs(buffers, mode, step);
+        }
+
+        for post in node.successors.iter() {
+            self.nodes[*post].pending.fetch_sub(1, Ordering::AcqRel);
+        }
+        self.remaining.fetch_sub(1, Ordering::Release);
+    }
+
+    /// 把图流输出叠加到 `output`，只在两次运行之间调用
+    pub(crate) fn mix_outputs(&self, output: &mut [f32]) {
+        let output_delays = unsafe { &mut *self.output_delays.get() };
+        let output_gains = unsafe { &mut *self.output_gains.get() };
+        let step = fade_step(self.params().0.sample_rate());
+        let layout = ChannelLayout::from_channels(self.num_channels);
+        let outputs = self.outputs.iter().zip(output_delays.iter_mut()).zip(output_gains.iter_mut());
+        for ((((i, port), delay), gain), target) in outputs.zip(solo_targets(&self.output_solos, &self.ghost_outputs)) {
+            let (from, port) = delayed(&self.buffers(*i).outputs, *port, delay);
+            // 宿主的输出按声道交错排列
+            if gain.0 == target && from.layout(port) == layout {
+                if target == 1.0 {
+                    for (channel, samples) in from.channels(port).enumerate() {
+                        let output = output.iter_mut().skip(channel).step_by(self.num_channels);
+                        output.zip(samples.iter()).for_each(|(sample, block_sample)| *sample += *block_sample);
+                    }
+                }
+                continue;
+            }
+            let from_layout = from.layout(port);
+            let block_frame = &mut [0.0; MAX_FRAME_CHANNELS][..from_layout.num_channels()];
+            for (i, frame) in output.chunks_mut(self.num_channels).enumerate().take(from.num_frames()) {
+                let gain = gain.next(target, step);
+                from.read_frame(port, i, block_frame);
+                mix_frame(block_frame, from_layout, frame, layout, gain);
+            }
+        }
+    }
+
+    /// 图流输出的总延迟（帧）
+    pub(crate) fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// 某个块的输出相对于图流源头的延迟（帧）
+    pub(crate) fn block_latency(&self, block_id: &BlockId) -> Option<usize> {
+        self.index.get(block_id).filter(|i| !self.nodes[**i].ghost).map(|i| self.latencies[*i])
+    }
+
+    /// 写入图流输入，只在两次运行之间调用
+    pub(crate) fn write_graph_inputs(&self, inputs: &IOData) {
+        let graph_inputs = unsafe { &mut *self.graph_inputs.get() };
+        graph_inputs.set_buffer_size(inputs.buffer_size(), self.num_channels);
+        for port in 0..graph_inputs.port_len().min(inputs.port_len()) {
+            copy_port(inputs, port, graph_inputs, port);
+        }
+    }
+
+    /// 把某个块的输出端口经过 `delay` 复制到 `to`，只在两次运行之间调用
+    pub(crate) fn read_output(&self, port: Port, delay: &mut Option<DelayLine>, to: &mut IOData, to_port: usize) {
+        if let Some(i) = self.index.get(&port.block_id) {
+            let (from, from_port) = delayed(&self.buffers(*i).outputs, port.port, delay);
+            copy_port(from, from_port, to, to_port);
+        }
+    }
+
+    /// 读取某个块的输出端口，只在两次运行之间调用
+    pub(crate) fn with_output(&self, port: Port, f: impl FnOnce(&IOData, usize)) {
+        if let Some(i) = self.index.get(&port.block_id) {
+            f(&self.buffers(*i).outputs, port.port);
+        }
+    }
+
+    /// 对所有块调用 `Processor::reset` 并清空缓冲区，只在两次运行之间调用
+    pub(crate) fn reset(&self) {
+        for node in self.nodes.iter() {
+            unsafe { node.processor.get_mut() }.reset();
+        }
+        self.clear();
+    }
+
+    /// 清空所有输出与反馈，只在两次运行之间调用
+    pub(crate) fn clear(&self) {
+        for node in self.nodes.iter() {
+            let buffers = unsafe { &mut *node.buffers.get() };
+            for port in 0..buffers.outputs.port_len() {
+                buffers.outputs[port].fill(0.0);
+            }
+            buffers.outputs.clear_events();
+            if let Some(feedback) = buffers.feedback.as_mut() {
+                for port in 0..feedback.port_len() {
+                    feedback[port].fill(0.0);
+                }
+                feedback.clear_events();
+            }
+        }
+    }
+}
+
+/// 把上游端口叠加到输入端口，两端速率或声道布局不同时自动转换
+///
+/// 控制到音频：每段从上一个控制值线性过渡到当前值，`last` 保存跨缓冲区的上一个值；
+/// 音频到控制：取每段内所有声道的平均值；音频到音频：按 `mix_frame` 转换声道。
+/// `fade` 为连接正在淡入淡出时的（当前增益, 目标增益, 每帧步长），控制值按每段第一帧的增益；
+/// 事件不能淡化，直接跟随目标。
+fn mix_port(
+    from: &IOData,
+    from_port: usize,
+    to: &mut IOData,
+    to_port: usize,
+    last: &mut f32,
+    mut fade: Option<(&mut Fade, f32, f32)>,
+) {
+    let interval = to.control_interval();
+    let (from_layout, to_layout) = (from.layout(from_port), to.layout(to_port));
+    let faded = fade.is_some();
+    let mut gain = |frames: usize| fade.as_mut().map_or(1.0, |(gain, target, step)| gain.next(*target, *step * frames as f32));
+    match (from.kind(from_port), to.kind(to_port)) {
+        (SignalKind::Event, _) | (_, SignalKind::Event) => {
+            let target = fade.map_or(1.0, |(gain, target, _)| {
+                gain.0 = target;
+                target
+            });
+            if target == 1.0 {
+                to.events_mut(to_port).merge(from.events(from_port));
+            }
+        }
+        (SignalKind::Control, SignalKind::Audio) => {
+            let values = &from[from_port];
+            let mut prev = if last.is_nan() { values.first().copied().unwrap_or(0.0) } else { *last };
+            let num_frames = to.num_frames();
+            for (start, value) in (0..num_frames).step_by(interval).zip(values.iter()) {
+                let step = (*value - prev) / interval as f32;
+                for j in 0..interval.min(num_frames - start) {
+                    let sample = (prev + step * (j + 1) as f32) * gain(1);
+                    to.channels_mut(to_port).for_each(|input| input[start + j] += sample);
+                }
+                prev = *value;
+            }
+            *last = prev;
+        }
+        (SignalKind::Audio, SignalKind::Control) => {
+            let num_frames = from.num_frames();
+            for (input, start) in to[to_port].iter_mut().zip((0..num_frames).step_by(interval)) {
+                let end = (start + interval).min(num_frames);
+                let sum = from.channels(from_port).map(|samples| samples[start..end].iter().sum::<f32>()).sum::<f32>();
+                *input += sum / ((end - start) * from_layout.num_channels()) as f32 * gain(interval);
+            }
+        }
+        (SignalKind::Control, SignalKind::Control) if faded => to[to_port].iter_mut().zip(from[from_port].iter())
+            .for_each(|(input, from_data)| *input += *from_data * gain(interval)),
+        _ if faded || from_layout != to_layout => mix_channels(from, from_port, to, to_port, || gain(1)),
+        _ => to[to_port].iter_mut().zip(from[from_port].iter())
+            .for_each(|(input, from_data)| *input += *from_data),
+    }
+}
+
+/// 各输出的目标增益：有输出独奏时只保留独奏的输出，移出的输出淡出
+fn solo_targets<'a>(solos: &'a [SoloHandle], ghosts: &'a [bool]) -> impl Iterator<Item = f32> + 'a {
+    let any_solo = solos.iter().zip(ghosts.iter()).any(|(solo, ghost)| !ghost && solo.get());
+    solos.iter().zip(ghosts.iter())
+        .map(move |(solo, ghost)| if !ghost && (!any_solo || solo.get()) { 1.0 } else { 0.0 })
+}
+
+/// 在处理结果与旁通信号之间逐帧淡化
+///
+/// 旁通信号取同一下标、同一种类的输入端口，声道布局不同时按 `mix_frame` 转换；
+/// 控制端口按每段第一帧的增益淡化。事件不能淡化，直接跟随目标模式。
+fn switch_outputs(buffers: &mut NodeBuffers, mode: BlockMode, step: f32) {
+    let (active, bypass) = mode.gains();
+    let NodeBuffers { inputs, outputs, active_gain, bypass_gain, .. } = buffers;
+    let interval = outputs.control_interval();
+    let num_frames = outputs.num_frames();
+    let matching = |outputs: &IOData, port: usize| {
+        (port < inputs.port_len() && inputs.kind(port) == outputs.kind(port)).then_some(port)
+    };
+
+    let (output_frame, input_frame) = (&mut [0.0; MAX_FRAME_CHANNELS], &mut [0.0; MAX_FRAME_CHANNELS]);
+    for frame in 0..num_frames {
+        let (a, b) = (active_gain.next(active, step), bypass_gain.next(bypass, step));
+        for port in 0..outputs.port_len() {
+            let input = matching(outputs, port);
+            match outputs.kind(port) {
+                SignalKind::Audio => {
+                    let layout = outputs.layout(port);
+                    let output = &mut output_frame[..layout.num_channels()];
+                    outputs.read_frame(port, frame, output);
+                    output.iter_mut().for_each(|sample| *sample *= a);
+                    if let Some(input) = input {
+                        let input_layout = inputs.layout(input);
+                        let from = &mut input_frame[..input_layout.num_channels()];
+                        inputs.read_frame(input, frame, from);
+                        mix_frame(from, input_layout, output, layout, b);
+                    }
+                    outputs.write_frame(port, frame, output);
+                }
+                SignalKind::Control if frame % interval == 0 => {
+                    let j = frame / interval;
+                    let through = input.map_or(0.0, |input| inputs[input][j]);
+                    outputs[port][j] = outputs[port][j] * a + through * b;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if mode != BlockMode::Active {
+        for port in 0..outputs.port_len() {
+            if outputs.kind(port) != SignalKind::Event {
+                continue;
+            }
+            let input = matching(outputs, port);
+            let events = outputs.events_mut(port);
+            events.clear();
+            if let (BlockMode::Bypass, Some(input)) = (mode, input) {
+                events.merge(inputs.events(input));
+            }
+        }
+    }
+}
+
+/// 一次淡化的帧数，见 `fade_step`
+fn fade_frames(time: Time) -> usize {
+    (time.sample_rate() as f32 * SWITCH_FADE_SECS).ceil() as usize
+}
+
+/// 经过延迟补偿后的上游端口，没有补偿时原样返回
+fn delayed<'a>(from: &'a IOData, from_port: usize, delay: &'a mut Option<DelayLine>) -> (&'a IOData, usize) {
+    match delay {
+        Some(delay) => (delay.process(from, from_port), 0),
+        None => (from, from_port),
+    }
+}
+
+/// 把一个端口延迟固定的帧数
+pub(crate) struct DelayLine {
+    frames: usize,
+    /// 环形缓冲区：音频每个声道 `frames` 个采样依次存放，控制为按控制间隔取整后的值数
+    ring: Vec<f32>,
+    position: usize,
+    /// 还没到时间的事件，帧偏移相对于下一个缓冲区的起点
+    pending: EventBuffer,
+    /// 只有一个端口，种类与上游端口相同
+    output: IOData,
+    num_channels: usize,
+}
+
+impl DelayLine {
+    pub(crate) fn new(frames: usize, spec: &PortSpec, buffer_size: usize, format: Format) -> Self {
+        let Format { max_buffer_size, num_channels, control_interval } = format;
+        let len = match spec.kind {
+            SignalKind::Audio => frames * spec.resolve_layout(num_channels).num_channels(),
+            SignalKind::Control => (frames + control_interval / 2) / control_interval,
+            SignalKind::Event => 0,
+        };
+        let pending = match spec.kind {
+            SignalKind::Event => EventBuffer::with_capacity(EVENT_CAPACITY),
+            _ => EventBuffer::new(),
+        };
+        DelayLine {
+            frames,
+            ring: vec![0.0; len],
+            position: 0,
+            pending,
+            output: IOData::with_capacity(std::slice::from_ref(spec), buffer_size, max_buffer_size, num_channels, control_interval),
+            num_channels,
+        }
+    }
+
+    /// 清空延迟中的数据，不重新分配
+    pub(crate) fn reset(&mut self) {
+        self.ring.fill(0.0);
+        self.position = 0;
+        self.pending.clear();
+    }
+
+    fn process(&mut self, from: &IOData, from_port: usize) -> &IOData {
+        if self.output.buffer_size() != from.buffer_size() {
+            self.output.set_buffer_size(from.buffer_size(), self.num_channels);
+        }
+        if from.kind(from_port) == SignalKind::Event {
+            for event in from.events(from_port) {
+                self.pending.push(Event { frame: event.frame + self.frames, message: event.message });
+            }
+            let events = self.output.events_mut(0);
+            events.clear();
+            self.pending.drain_before(from.num_frames(), events);
+        } else if self.ring.is_empty() {
+            self.output[0].copy_from_slice(&from[from_port]);
+        } else if from.kind(from_port) == SignalKind::Audio {
+            let num_frames = from.num_frames();
+            for frame in 0..num_frames {
+                for (channel, ring) in self.ring.chunks_exact_mut(self.frames).enumerate() {
+                    let input = from[from_port][channel * num_frames + frame];
+                    self.output[0][channel * num_frames + frame] = std::mem::replace(&mut ring[self.position], input);
+                }
+                self.position = (self.position + 1) % self.frames;
+            }
+        } else {
+            for (output, input) in self.output[0].iter_mut().zip(from[from_port].iter()) {
+                *output = std::mem::replace(&mut self.ring[self.position], *input);
+                self.position = (self.position + 1) % self.ring.len();
+            }
+        }
+        &self.output
+    }
+}
+
+fn copy_port(from: &IOData, from_port: usize, to: &mut IOData, to_port: usize) {
+    to[to_port].iter_mut().zip(from[from_port].iter()).for_each(|(to, from)| *to = *from);
+    let events = to.events_mut(to_port);
+    events.clear();
+    events.merge(from.events(from_port));
+}
+
+/// 把宿主任意长度的缓冲区切成固定长度的块，末尾不足一块的输出留到下一次
+#[derive(Default)]
+pub(crate) struct OutputQueue {
+    samples: Vec<f32>,
+    /// `samples` 末尾还没交出去的采样数
+    queued: usize,
+}
+
+impl OutputQueue {
+    pub(crate) fn new(block_size: usize) -> Self {
+        OutputQueue { samples: vec![0.0; block_size], queued: 0 }
+    }
+
+    pub(crate) fn queued(&self) -> usize {
+        self.queued
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.queued = 0;
+    }
+
+    /// 填满 `output`，`render` 每次处理一个块
+    pub(crate) fn fill(&mut self, output: &mut [f32], mut render: impl FnMut(&mut [f32])) {
+        let block_size = self.samples.len();
+        let mut written = self.queued.min(output.len());
+        output[..written].copy_from_slice(&self.samples[block_size - self.queued..][..written]);
+        self.queued -= written;
+        while output.len() - written >= block_size {
+            render(&mut output[written..written + block_size]);
+            written += block_size;
+        }
+        if written < output.len() {
+            render(&mut self.samples);
+            let rest = output.len() - written;
+            output[written..].copy_from_slice(&self.samples[..rest]);
+            self.queued = block_size - rest;
+        }
+    }
+}
+
+struct PoolShared {
+    plan: Mutex<Arc<Plan>>,
+    shutdown: AtomicBool,
+}
+
+/// 常驻的工作线程，被唤醒后帮助音频线程处理当前计划
+pub(crate) struct ThreadPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    shared: Arc<PoolShared>,
+}
+
+impl ThreadPool {
+    pub(crate) fn new(size: usize, plan: Arc<Plan>) -> ThreadPool {
+        let shared = Arc::new(PoolShared {
+            plan: Mutex::new(plan),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let workers = (0..size).map(|_| {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || loop {
+                thread::park();
+                if shared.shutdown.load(Ordering::Acquire) {
+                    break;
+                }
+                // 锁只在工作线程一侧，音频线程从不等待它
+                let plan = Arc::clone(&shared.plan.lock().unwrap());
+                while plan.remaining.load(Ordering::Acquire) > 0 {
+                    if !plan.work() {
+                        std::hint::spin_loop();
+                    }
+                }
+            })
+        }).collect();
+
+        ThreadPool { workers, shared }
+    }
+
+    /// 换上新编译的计划（不在音频线程的关键路径上）
+    pub(crate) fn set_plan(&self, plan: Arc<Plan>) {
+        *self.shared.plan.lock().unwrap() = plan;
+    }
+
+    /// 在音频线程上换计划：工作线程正持有锁时返回 false，由调用者下一个缓冲区再试
+    ///
+    /// 池中原来的计划仍被调用者持有，这里不会释放它。
+    pub(crate) fn try_set_plan(&self, plan: &Arc<Plan>) -> bool {
+        match self.shared.plan.try_lock() {
+            Ok(mut current) => {
+                *current = Arc::clone(plan);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn wake(&self) {
+        for worker in self.workers.iter() {
+            worker.thread().unpark();
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        self.wake();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! 子图：把一个 `GraphFlow` 作为另一个图流中的块
+//!
+//! 子图暴露选定的内部端口作为自己的输入输出，在外层的 `run` 中运行内部的运行计划。
+//! 内部图流的采样率、声道数与控制间隔跟随外层。内部的工作线程在创建子图时停掉，
+//! 子图总在认领它的线程上直接运行，嵌套或作为 `Poly` 的声部时不会成倍增加线程。
+
+use crate::block::{BlockId, IOData, Port, PortDirection, PortLayout, PortRef, PortSpec, Processor, Time};
+use crate::graph_flow::{GraphError, GraphFlow};
+use crate::param::ParamHandle;
+use crate::schedule::DelayLine;
+
+pub struct Subgraph {
+    graph: GraphFlow,
+    ports: PortLayout,
+    /// 与 `ports.outputs` 一一对应的内部输出端口
+    outputs: Vec<Port>,
+    /// 与 `outputs` 一一对应，把各输出对齐到其中最大的延迟，在 `prepare` 中重建
+    delays: Vec<Option<DelayLine>>,
+}
+
+impl Subgraph {
+    pub fn new(mut graph: GraphFlow) -> Self {
+        assert!(!graph.is_split(), "A split GraphFlow cannot be nested");
+        graph.stop_workers();
+        Subgraph { graph, ports: PortLayout::new(), outputs: Vec::new(), delays: Vec::new() }
+    }
+
+    /// 把内部输入端口暴露为子图的输入 `name`，同名的输入可以连到多个内部端口
+    pub fn input(mut self, name: &str, to: impl Into<PortRef>) -> Result<Self, GraphError> {
+        self.graph.connect_graph_input(name, to.into())?;
+        self.ports.inputs = self.graph.graph_inputs().to_vec();
+        Ok(self)
+    }
+
+    /// 把内部输出端口暴露为子图的输出 `name`
+    pub fn output(mut self, name: &str, from: impl Into<PortRef>) -> Result<Self, GraphError> {
+        let from = self.graph.resolve(from.into(), PortDirection::Output)?;
+        if self.ports.find(PortDirection::Output, name).is_some() {
+            return Err(GraphError::DuplicatePortName(name.to_string()));
+        }
+        let spec = self.graph.spec(from, PortDirection::Output);
+        self.ports.outputs.push(PortSpec { name: name.to_string(), ..spec.clone() });
+        self.outputs.push(from);
+        Ok(self)
+    }
+
+    pub fn graph(&self) -> &GraphFlow {
+        &self.graph
+    }
+
+    /// 内部块的参数句柄
+    pub fn param(&self, block_id: &BlockId, name: &str) -> Result<ParamHandle, GraphError> {
+        self.graph.param(block_id, name)
+    }
+}
+
+impl Processor for Subgraph {
+    fn prepare(&mut self, sample_rate: u32, max_buffer: usize, num_channels: usize) {
+        self.graph.set_format(sample_rate, max_buffer as u32, num_channels);
+        self.delays = self.graph.align_outputs(&self.outputs);
+    }
+
+    fn set_control_interval(&mut self, control_interval: usize) {
+        self.graph.set_control_interval(control_interval);
+    }
+
+    fn reset(&mut self) {
+        self.graph.reset();
+        self.delays.iter_mut().flatten().for_each(DelayLine::reset);
+    }
+
+    fn ports(&self) -> PortLayout {
+        self.ports.clone()
+    }
+
+    /// 内部所有块的参数，外层的自动化与 UI 可以直接使用
+    fn params(&self) -> Vec<ParamHandle> {
+        self.graph.all_params()
+    }
+
+    /// 暴露的输出中最大的延迟，其余输出在 `process` 中延迟到与它对齐
+    fn latency(&self) -> usize {
+        self.outputs.iter().filter_map(|port| self.graph.block_latency(&port.block_id).ok()).max().unwrap_or(0)
+    }
+
+    fn process(&mut self, time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+        self.graph.run_nested(time, inputs, outputs, &self.outputs, &mut self.delays);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, SignalKind};
+    use crate::graph_flow::GraphFlowBuilder;
+
+    fn gain(_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+        outputs[0].iter_mut().zip(inputs[0].iter()).for_each(|(output, input)| *output = *input * 2.0);
+    }
+
+    #[test]
+    fn nested_graph_flow() {
+        let builder = GraphFlowBuilder { buffer_size: 8, num_threads: 0, ..Default::default() };
+
+        // 内部：输入乘 2，再加上一个内部的常量；内部的工作线程在创建子图时停掉
+        let mut inner = GraphFlowBuilder { num_threads: 2, ..builder }.build();
+        let layout = PortLayout::new().input("in", SignalKind::Audio).output("out", SignalKind::Audio);
+        let amp = inner.add_block(Block::with_ports(gain, layout)).unwrap();
+        let offset = inner.add_block(|_time: Time, outputs: &mut IOData, _num_channels: usize| outputs[0].fill(0.5)).unwrap();
+        let mix = inner.add_block(|_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
+            outputs[0].copy_from_slice(&inputs[0]);
+        }).unwrap();
+        inner.connect(amp.named("out"), mix.port(0)).unwrap();
+        inner.connect(offset.port(0), mix.port(0)).unwrap();
+        let subgraph = Subgraph::new(inner)
+            .input("in", amp.named("in")).unwrap()
+            .output("out", mix.port(0)).unwrap();
+        assert!(matches!(
+            Subgraph::new(builder.build()).output("out", amp.named("out")),
+            Err(GraphError::UnknownBlock(_)),
+        ));
+
+        let mut gf = GraphFlowBuilder { sample_rate: 44100, ..builder }.build();
+        let source = gf.add_block(|_time: Time, outputs: &mut IOData, _num_channels: usize| outputs[0].fill(1.0)).unwrap();
+        let sub = gf.add_block(subgraph).unwrap();
+        assert_eq!(gf.ports(&sub).unwrap().inputs[0].name, "in");
+        gf.connect(source.port(0), sub.named("in")).unwrap();
+        gf.to_output(sub.named("out")).unwrap();
+
+        let mut output = vec![0.0; 8];
+        for _ in 0..2 {
+            gf.run(8, &mut output);
+            assert!(output.iter().all(|&sample| sample == 2.5));
+        }
+    }
+
+    /// 把输入延迟三帧，并如实报告延迟
+    struct Late([f32; 3]);
+
+    impl Processor for Late {
+        fn latency(&self) -> usize {
+            3
+        }
+
+        fn process(&mut self, _time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+            for (output, input) in outputs[0].iter_mut().zip(inputs[0].iter()) {
+                *output = self.0[0];
+                self.0 = [self.0[1], self.0[2], *input];
+            }
+        }
+    }
+
+    #[test]
+    fn exposed_outputs_are_aligned() {
+        let builder = GraphFlowBuilder { buffer_size: 8, num_channels: 1, num_threads: 0, ..Default::default() };
+        let mut inner = builder.build();
+        let early = inner.add_block(|_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
+            outputs[0].copy_from_slice(&inputs[0]);
+        }).unwrap();
+        let late = inner.add_block(Late([0.0; 3])).unwrap();
+        let subgraph = Subgraph::new(inner)
+            .input("in", early.port(0)).unwrap()
+            .input("in", late.port(0)).unwrap()
+            .output("early", early.port(0)).unwrap()
+            .output("late", late.port(0)).unwrap();
+
+        let mut gf = builder.build();
+        let source = gf.add_block(|_time: Time, outputs: &mut IOData, _num_channels: usize| outputs[0].fill(1.0)).unwrap();
+        let sub = gf.add_block(subgraph).unwrap();
+        gf.connect(source.port(0), sub.named("in")).unwrap();
+        gf.to_output(sub.named("early")).unwrap();
+        gf.to_output(sub.named("late")).unwrap();
+        assert_eq!(gf.latency(), 3);
+
+        // 两路输出同时到达
+        let mut output = vec![0.0; 8];
+        gf.run(8, &mut output);
+        assert_eq!(output, [0.0, 0.0, 0.0, 2.0, 2.0, 2.0, 2.0, 2.0]);
+    }
+}
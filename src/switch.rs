@@ -0,0 +1,141 @@
+//! 音频块的旁通、静音与输出的独奏
+//!
+//! 开关都是原子量，可以在播放中从任意线程切换，不需要重新编译运行计划。
+//! 切换时运行计划在 `SWITCH_FADE_SECS` 内交叉淡化，避免爆音。
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+/// 切换模式或独奏时的淡化时长（秒）
+pub const SWITCH_FADE_SECS: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockMode {
+    /// 正常处理
+    #[default]
+    Active,
+    /// 不处理，输入直接送到同一下标、同一种类的输出，其余输出静音
+    Bypass,
+    /// 不处理，输出静音
+    Mute,
+}
+
+impl BlockMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => BlockMode::Bypass,
+            2 => BlockMode::Mute,
+            _ => BlockMode::Active,
+        }
+    }
+
+    /// 处理结果与旁通信号的目标增益
+    pub(crate) fn gains(self) -> (f32, f32) {
+        match self {
+            BlockMode::Active => (1.0, 0.0),
+            BlockMode::Bypass => (0.0, 1.0),
+            BlockMode::Mute => (0.0, 0.0),
+        }
+    }
+}
+
+/// 音频块模式的句柄，可以交给 UI 线程
+#[derive(Debug, Clone, Default)]
+pub struct ModeHandle(Arc<AtomicU8>);
+
+impl ModeHandle {
+    pub fn get(&self) -> BlockMode {
+        BlockMode::from_u8(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, mode: BlockMode) {
+        self.0.store(mode as u8, Ordering::Relaxed);
+    }
+}
+
+/// 输出端口独奏的句柄；任一输出独奏时，只有独奏的输出混入图流输出
+#[derive(Debug, Clone, Default)]
+pub struct SoloHandle(Arc<AtomicBool>);
+
+impl SoloHandle {
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, solo: bool) {
+        self.0.store(solo, Ordering::Relaxed);
+    }
+}
+
+/// 线性趋近目标的增益
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Fade(pub(crate) f32);
+
+impl Fade {
+    pub(crate) fn next(&mut self, target: f32, step: f32) -> f32 {
+        self.0 = if self.0 < target { (self.0 + step).min(target) } else { (self.0 - step).max(target) };
+        self.0
+    }
+}
+
+/// 每帧的增益步长
+pub(crate) fn fade_step(sample_rate: u32) -> f32 {
+    1.0 / (sample_rate as f32 * SWITCH_FADE_SECS).max(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::approx_eq;
+    use crate::block::{IOData, Time};
+    use crate::graph_flow::{GraphError, GraphFlowBuilder};
+
+    #[test]
+    fn bypass_mute_and_solo() {
+        // 淡化 10 帧，每个缓冲区 10 帧
+        let mut gf = GraphFlowBuilder { sample_rate: 1000, buffer_size: 10, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let source = gf.add_block(|_time: Time, outputs: &mut IOData, _num_channels: usize| outputs[0].fill(1.0)).unwrap();
+        let gain = gf.add_block(|_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
+            outputs[0].iter_mut().zip(inputs[0].iter()).for_each(|(output, input)| *output = *input * 3.0);
+        }).unwrap();
+        gf.connect(source.port(0), gain.port(0)).unwrap();
+        gf.to_output(gain.port(0)).unwrap();
+
+        let mut output = vec![0.0; 10];
+        let mut run = |gf: &mut crate::graph_flow::GraphFlow| {
+            output.fill(0.0);
+            gf.run(10, &mut output);
+            output.clone()
+        };
+        assert!(run(&mut gf).iter().all(|&sample| sample == 3.0));
+
+        // 旁通：从 3 逐帧过渡到输入的 1，之后不再处理
+        gf.set_mode(&gain, BlockMode::Bypass).unwrap();
+        let fade = run(&mut gf);
+        assert!(approx_eq(fade[0], 2.8) && approx_eq(fade[9], 1.0));
+        assert!(fade.windows(2).all(|pair| pair[0] > pair[1]));
+        assert!(run(&mut gf).iter().all(|&sample| sample == 1.0));
+
+        let mode = gf.mode(&gain).unwrap();
+        mode.set(BlockMode::Mute);
+        assert!(approx_eq(run(&mut gf)[9], 0.0));
+        assert!(run(&mut gf).iter().all(|&sample| sample == 0.0));
+        mode.set(BlockMode::Active);
+        assert!(approx_eq(run(&mut gf)[4], 1.5));
+
+        // 独奏：只有独奏的输出混入
+        gf.to_output(source.port(0)).unwrap();
+        assert!(approx_eq(run(&mut gf)[0], 4.0));
+        gf.set_solo(source.port(0), true).unwrap();
+        let fade = run(&mut gf);
+        assert!(approx_eq(fade[0], 3.7) && approx_eq(fade[9], 1.0));
+        assert!(run(&mut gf).iter().all(|&sample| sample == 1.0));
+        gf.solo(source.port(0)).unwrap().set(false);
+        assert!(approx_eq(run(&mut gf)[9], 4.0));
+
+        assert_eq!(gf.set_solo(source.port(1), true), Err(GraphError::NotAnOutput(source.port(1))));
+    }
+}
@@ -0,0 +1,192 @@
+//! 探针：在不改变图结构的情况下读取任意输出端口
+//!
+//! 每次运行后，图流把被探测端口的采样写入一个单生产者单消费者的无锁环形缓冲区，
+//! UI 的电平表、示波器或测试在其他线程上通过 `Tap` 读取。缓冲区满时丢弃新的采样并计数，
+//! 音频线程从不等待读取端。
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::block::{IOData, Port, SignalKind};
+
+/// `GraphFlow::tap` 使用的缓冲区容量（采样数）
+pub const DEFAULT_TAP_CAPACITY: usize = 1 << 16;
+
+/// 图流的所有探针，拆分后与 `Engine` 共享；音频线程只 `try_lock`
+pub(crate) type Taps = Arc<Mutex<Vec<(Port, Arc<Ring>)>>>;
+
+pub(crate) struct Ring {
+    samples: Box<[AtomicU32]>,  // f32 的位
+    // 单调递增的写、读位置，对容量取模得到下标
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl Ring {
+    pub(crate) fn new(capacity: usize) -> Arc<Self> {
+        assert!(capacity > 0, "Tap capacity must be greater than 0");
+        Arc::new(Ring {
+            samples: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// 由图流在两次运行之间调用，写不下的部分被丢弃
+    pub(crate) fn push_slice(&self, data: &[f32]) {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let count = data.len().min(self.capacity() - (head - tail));
+        for (i, sample) in data[..count].iter().enumerate() {
+            self.samples[(head + i) % self.capacity()].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.head.store(head + count, Ordering::Release);
+        if count < data.len() {
+            self.dropped.fetch_add(data.len() - count, Ordering::Relaxed);
+        }
+    }
+
+    /// 写入一个端口：多声道的音频端口按声道交错，只写入放得下的整帧
+    pub(crate) fn push_port(&self, data: &IOData, port: usize) {
+        let num_channels = data.layout(port).num_channels();
+        if data.kind(port) != SignalKind::Audio || num_channels == 1 {
+            return self.push_slice(&data[port]);
+        }
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let num_frames = data.num_frames();
+        let frames = num_frames.min((self.capacity() - (head - tail)) / num_channels);
+        for (channel, samples) in data.channels(port).enumerate() {
+            for (frame, sample) in samples[..frames].iter().enumerate() {
+                self.samples[(head + frame * num_channels + channel) % self.capacity()].store(sample.to_bits(), Ordering::Relaxed);
+            }
+        }
+        self.head.store(head + frames * num_channels, Ordering::Release);
+        if frames < num_frames {
+            self.dropped.fetch_add((num_frames - frames) * num_channels, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 端口探针的读取端，丢弃后图流不再写入
+///
+/// 音频端口的采样按声道交错排列，控制端口每个控制间隔一个值。
+pub struct Tap {
+    ring: Arc<Ring>,
+    port: Port,
+    kind: SignalKind,
+}
+
+impl Tap {
+    pub(crate) fn new(ring: Arc<Ring>, port: Port, kind: SignalKind) -> Self {
+        Tap { ring, port, kind }
+    }
+
+    pub fn port(&self) -> Port {
+        self.port
+    }
+
+    pub fn kind(&self) -> SignalKind {
+        self.kind
+    }
+
+    /// 可以读取的采样数
+    pub fn len(&self) -> usize {
+        self.ring.head.load(Ordering::Acquire) - self.ring.tail.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn pop(&mut self) -> Option<f32> {
+        let mut sample = [0.0];
+        (self.read(&mut sample) == 1).then_some(sample[0])
+    }
+
+    /// 读出最多 `buffer.len()` 个采样，返回读到的数量
+    pub fn read(&mut self, buffer: &mut [f32]) -> usize {
+        let ring = &self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let count = buffer.len().min(ring.head.load(Ordering::Acquire) - tail);
+        for (i, sample) in buffer[..count].iter_mut().enumerate() {
+            *sample = f32::from_bits(ring.samples[(tail + i) % ring.capacity()].load(Ordering::Relaxed));
+        }
+        ring.tail.store(tail + count, Ordering::Release);
+        count
+    }
+
+    /// 丢弃积压的采样，只保留最新的 `keep` 个，适合只关心当前画面的示波器
+    pub fn skip_to_latest(&mut self, keep: usize) {
+        let skip = self.len().saturating_sub(keep);
+        self.ring.tail.fetch_add(skip, Ordering::Release);
+    }
+
+    /// 缓冲区满时被丢弃的采样数
+    pub fn dropped(&self) -> usize {
+        self.ring.dropped.load(Ordering::Relaxed)
+    }
+
+    /// 被探测的块被删除后返回 `false`
+    pub fn is_active(&self) -> bool {
+        Arc::strong_count(&self.ring) > 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::{IOData, Processor, Time};
+    use crate::graph_flow::{GraphError, GraphFlowBuilder};
+    use crate::event::Sequencer;
+
+    struct Counter(f32);
+
+    impl Processor for Counter {
+        fn process(&mut self, _time: Time, _inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+            for sample in outputs[0].iter_mut() {
+                self.0 += 1.0;
+                *sample = self.0;
+            }
+        }
+    }
+
+    #[test]
+    fn tap_ports() {
+        let mut gf = GraphFlowBuilder { buffer_size: 4, num_channels: 1, num_threads: 0, ..Default::default() }.build();
+        let counter = gf.add_block(Counter(0.0)).unwrap();
+        let pass = gf.add_block(|_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize| {
+            outputs[0].iter_mut().zip(inputs[0].iter()).for_each(|(output, input)| *output = -*input);
+        }).unwrap();
+        gf.connect(counter.port(0), pass.port(0)).unwrap();
+        let mut probe = gf.tap(counter.port(0)).unwrap();
+        let mut small = gf.tap_with_capacity(pass.port(0), 6).unwrap();
+
+        // 没有连到输出的端口也能探测
+        let mut output = vec![0.0; 4];
+        for _ in 0..2 {
+            gf.run(4, &mut output);
+        }
+        assert_eq!(probe.len(), 8);
+        let mut samples = vec![0.0; 16];
+        assert_eq!(probe.read(&mut samples), 8);
+        assert_eq!(samples[..8], [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        assert!(probe.is_empty());
+
+        assert_eq!(small.dropped(), 2);
+        small.skip_to_latest(2);
+        assert_eq!((small.pop(), small.pop(), small.pop()), (Some(-5.0), Some(-6.0), None));
+
+        drop(probe);
+        gf.remove_block(pass);
+        assert!(!small.is_active());
+
+        let sequencer = gf.add_block(Sequencer::new(Vec::new(), 1)).unwrap();
+        assert!(matches!(gf.tap(sequencer.named("events")), Err(GraphError::KindMismatch { .. })));
+    }
+}
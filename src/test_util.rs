@@ -0,0 +1,13 @@
+//! 测试共用的块
+
+use crate::block::{IOData, Time};
+
+/// 第一个输出恒为 1
+pub(crate) fn constant(_time: Time, outputs: &mut IOData, _num_channels: usize) {
+    outputs[0].fill(1.0);
+}
+
+/// 第一个输入原样送到第一个输出
+pub(crate) fn pass(_time: Time, inputs: &IOData, outputs: &mut IOData, _num_channels: usize) {
+    outputs[0].copy_from_slice(&inputs[0]);
+}